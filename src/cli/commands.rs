@@ -0,0 +1,439 @@
+use crate::{
+    config::{self, Config, Section},
+    sql::sql,
+    transport::omikron_ca,
+    util::crypto_helper::{
+        generate_keypair, load_public_key, public_key_to_base64, secret_key_to_base64,
+    },
+};
+use anyhow::{Context, anyhow, bail};
+use json::JsonValue;
+use std::{fs, path::Path, path::PathBuf};
+
+pub const USAGE: &str = "Usage: omega [--config <file>] <command>
+
+Commands:
+  serve                                     Start the HTTPS and Epsilon listeners (default)
+  check-config                              Load and validate the configuration
+  keygen                                    Generate a new x448 key pair for Omega
+  migrate                                   Create missing database tables
+  ca init                                   Create the CA for Omikron client certificates
+  omikron list                              List registered Omikrons
+  omikron add <public_key> <location> <ip>  Register an Omikron [--id <id>]
+  omikron remove <id>                       Remove an Omikron and close its connection
+  omikron cert <id>                         Issue a client certificate for an Omikron
+  omikron drain <id>                        Drain a running Omikron [--deadline <secs>]
+  user show <id|username>                   Show a user
+  user delete <id>                          Delete a user
+  help                                      Show this message";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Serve,
+    CheckConfig,
+    Keygen,
+    Migrate,
+    CaInit,
+    OmikronList,
+    OmikronAdd {
+        id: Option<i64>,
+        public_key: String,
+        location: String,
+        ip_address: String,
+    },
+    OmikronRemove {
+        id: i64,
+    },
+    OmikronCert {
+        id: i64,
+    },
+    OmikronDrain {
+        id: i64,
+        deadline_secs: Option<u64>,
+    },
+    UserShow {
+        query: String,
+    },
+    UserDelete {
+        id: i64,
+    },
+    Help,
+}
+
+#[derive(Debug)]
+pub struct Cli {
+    pub config_path: Option<PathBuf>,
+    pub command: Command,
+}
+
+// ============================================================================
+// Parsing
+// ============================================================================
+
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Cli, String> {
+    let mut config_path = None;
+    let mut id = None;
+    let mut deadline_secs = None;
+    let mut positional = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => {
+                let path = args.next().ok_or("--config needs a file")?;
+                config_path = Some(PathBuf::from(path));
+            }
+            "--id" => {
+                let value = args.next().ok_or("--id needs a value")?;
+                id = Some(parse_id(&value)?);
+            }
+            "--deadline" => {
+                let value = args.next().ok_or("--deadline needs a value")?;
+                let secs = value
+                    .parse::<u64>()
+                    .map_err(|_| format!("Invalid deadline: {}", value))?;
+                deadline_secs = Some(secs);
+            }
+            "-h" | "--help" => positional = vec!["help".to_string()],
+            _ => positional.push(arg),
+        }
+    }
+
+    let parts: Vec<&str> = positional.iter().map(String::as_str).collect();
+    let command = match parts.as_slice() {
+        [] | ["serve"] => Command::Serve,
+        ["check-config"] => Command::CheckConfig,
+        ["keygen"] => Command::Keygen,
+        ["migrate"] => Command::Migrate,
+        ["ca", "init"] => Command::CaInit,
+        ["omikron", "list"] => Command::OmikronList,
+        ["omikron", "add", public_key, location, ip_address] => Command::OmikronAdd {
+            id,
+            public_key: public_key.to_string(),
+            location: location.to_string(),
+            ip_address: ip_address.to_string(),
+        },
+        ["omikron", "remove", id] => Command::OmikronRemove { id: parse_id(id)? },
+        ["omikron", "cert", id] => Command::OmikronCert { id: parse_id(id)? },
+        ["omikron", "drain", id] => Command::OmikronDrain {
+            id: parse_id(id)?,
+            deadline_secs,
+        },
+        ["user", "show", query] => Command::UserShow {
+            query: query.to_string(),
+        },
+        ["user", "delete", id] => Command::UserDelete { id: parse_id(id)? },
+        ["help"] => Command::Help,
+        _ => return Err(format!("Unknown command: {}", parts.join(" "))),
+    };
+
+    Ok(Cli {
+        config_path,
+        command,
+    })
+}
+
+fn parse_id(value: &str) -> Result<i64, String> {
+    match value.parse::<i64>() {
+        Ok(id) if id > 0 => Ok(id),
+        _ => Err(format!("Invalid id: {}", value)),
+    }
+}
+
+// ============================================================================
+// Commands
+// ============================================================================
+
+/// Runs every command except `serve`.
+pub async fn run(cli: Cli) -> anyhow::Result<()> {
+    match cli.command {
+        Command::Serve => bail!("serve is handled by main"),
+        Command::Help => println!("{}", USAGE),
+        Command::Keygen => {
+            let (secret, public) = generate_keypair();
+            println!("PRIVATE_KEY={}", secret_key_to_base64(&secret));
+            println!("PUBLIC_KEY={}", public_key_to_base64(&public));
+        }
+        Command::CheckConfig => {
+            Config::load(cli.config_path.as_deref())?;
+            println!("Configuration OK");
+        }
+        command => {
+            let sections = required_sections(&command);
+            config::init(Config::load_sections(cli.config_path.as_deref(), sections)?);
+            run_with_config(command).await?;
+        }
+    }
+    Ok(())
+}
+
+/// The parts of the configuration a command uses, only those are validated.
+fn required_sections(command: &Command) -> &'static [Section] {
+    match command {
+        Command::CaInit | Command::OmikronCert { .. } => &[],
+        Command::OmikronDrain { .. } => &[Section::Admin],
+        Command::OmikronRemove { .. } => &[Section::Database, Section::Admin],
+        Command::Migrate
+        | Command::OmikronList
+        | Command::OmikronAdd { .. }
+        | Command::UserShow { .. }
+        | Command::UserDelete { .. } => &[Section::Database],
+        Command::Serve | Command::CheckConfig | Command::Keygen | Command::Help => Section::ALL,
+    }
+}
+
+async fn connect_db() -> anyhow::Result<()> {
+    sql::initialize_db(&config::get().database)
+        .await
+        .context("Could not connect to the database")
+}
+
+async fn run_with_config(command: Command) -> anyhow::Result<()> {
+    match command {
+        Command::CaInit => {
+            omikron_ca::create_ca()?;
+            println!("Created Omikron CA");
+            println!("Set transport.require_client_cert once every Omikron has a certificate");
+        }
+        Command::OmikronCert { id } => {
+            let (cert_pem, key_pem) = omikron_ca::issue_certificate(id)?;
+            let cert_file = format!("omikron_{}_cert.pem", id);
+            let key_file = format!("omikron_{}_key.pem", id);
+            fs::write(&cert_file, cert_pem)?;
+            omikron_ca::write_private_key(Path::new(&key_file), &key_pem)?;
+            println!("Wrote {} and {}", cert_file, key_file);
+        }
+        Command::OmikronDrain { id, deadline_secs } => {
+            let mut body = JsonValue::new_object();
+            if let Some(secs) = deadline_secs {
+                body["deadline_secs"] = secs.into();
+            }
+            let res = admin_request(&format!("omikrons/{}/drain", id), body).await?;
+            println!("Omikron {} is draining until {}", id, res["deadline"]);
+        }
+        Command::Migrate => {
+            connect_db().await?;
+            sql::migrate().await?;
+            println!("Database is up to date");
+        }
+        Command::OmikronList => {
+            connect_db().await?;
+            for (id, public_key, location, ip_address) in sql::get_omikrons().await? {
+                println!("{}\t{}\t{}\t{}", id, location, ip_address, public_key);
+            }
+        }
+        Command::OmikronAdd {
+            id,
+            public_key,
+            location,
+            ip_address,
+        } => {
+            if load_public_key(&public_key).is_none() {
+                bail!("Not a base64 encoded x448 public key: {}", public_key);
+            }
+            connect_db().await?;
+            let id = match id {
+                Some(id) => id,
+                None => sql::get_register_id().await as i64,
+            };
+            sql::register_omikron(id, public_key, location, ip_address).await?;
+            println!("Registered Omikron {}", id);
+        }
+        Command::OmikronRemove { id } => {
+            // The running Omega also closes the connection of the Omikron
+            if config::get().admin.enabled() {
+                let path = format!("omikrons/{}/decommission", id);
+                match admin_request(&path, JsonValue::new_object()).await {
+                    Ok(res) => {
+                        println!("Removed Omikron {}", id);
+                        if res["disconnected"].as_bool() == Some(true) {
+                            println!("Closed its connection");
+                        }
+                        return Ok(());
+                    }
+                    Err(e) if !is_unreachable(&e) => return Err(e),
+                    Err(_) => println!("Omega is not running, removing it from the database"),
+                }
+            } else {
+                println!(
+                    "Warning: the admin API is disabled, a running Omega keeps an open connection of the Omikron until it disconnects"
+                );
+            }
+
+            connect_db().await?;
+            if !sql::delete_omikron(id).await? {
+                bail!("Omikron {} not found", id);
+            }
+            println!("Removed Omikron {}", id);
+        }
+        Command::UserShow { query } => {
+            connect_db().await?;
+            let user = match query.parse::<i64>() {
+                Ok(id) => sql::get_by_user_id(id).await,
+                Err(_) => sql::get_by_username(&query).await,
+            }
+            .map_err(|_| anyhow!("User {} not found", query))?;
+
+            let (id, iota_id, username, display, status, about, _, sub_level, sub_end, pk, _, _) =
+                user;
+            println!("id:         {}", id);
+            println!("username:   {}", username);
+            println!("display:    {}", display.unwrap_or_default());
+            println!("status:     {}", status.unwrap_or_default());
+            println!("about:      {}", about.unwrap_or_default());
+            println!("iota_id:    {}", iota_id);
+            println!("sub_level:  {}", sub_level);
+            println!("sub_end:    {}", sub_end);
+            println!("public_key: {}", pk);
+        }
+        Command::UserDelete { id } => {
+            connect_db().await?;
+            sql::get_by_user_id(id)
+                .await
+                .map_err(|_| anyhow!("User {} not found", id))?;
+            sql::delete_user(id).await?;
+            println!("Deleted user {}", id);
+        }
+        Command::Serve | Command::CheckConfig | Command::Keygen | Command::Help => {}
+    }
+    Ok(())
+}
+
+/// Whether `admin_request` failed because no Omega is listening.
+fn is_unreachable(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| e.is_connect())
+}
+
+/// Sends a request to the admin API of the running Omega.
+async fn admin_request(path: &str, body: JsonValue) -> anyhow::Result<JsonValue> {
+    let admin = &config::get().admin;
+    if !admin.enabled() {
+        bail!("The admin API is disabled, set admin.token");
+    }
+    let host = match admin.bind.as_str() {
+        "0.0.0.0" => "127.0.0.1",
+        bind => bind,
+    };
+    let url = format!("http://{}:{}/admin/{}", host, admin.port, path);
+
+    let response = reqwest::Client::new()
+        .post(&url)
+        .bearer_auth(&admin.token)
+        .body(body.dump())
+        .send()
+        .await
+        .with_context(|| format!("Could not reach the admin API at {}", url))?;
+
+    let status = response.status();
+    let text = response.text().await?;
+    if !status.is_success() {
+        bail!("Admin API answered {}: {}", status, text);
+    }
+    Ok(json::parse(&text)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &str) -> Result<Command, String> {
+        parse(args.split_whitespace().map(str::to_string)).map(|cli| cli.command)
+    }
+
+    #[test]
+    fn parses_every_command() {
+        let cases = [
+            ("", Command::Serve),
+            ("serve", Command::Serve),
+            ("check-config", Command::CheckConfig),
+            ("keygen", Command::Keygen),
+            ("migrate", Command::Migrate),
+            ("ca init", Command::CaInit),
+            ("omikron list", Command::OmikronList),
+            (
+                "omikron add KEY eu-west 192.0.2.1",
+                Command::OmikronAdd {
+                    id: None,
+                    public_key: "KEY".to_string(),
+                    location: "eu-west".to_string(),
+                    ip_address: "192.0.2.1".to_string(),
+                },
+            ),
+            (
+                "omikron add --id 5 KEY eu-west 192.0.2.1",
+                Command::OmikronAdd {
+                    id: Some(5),
+                    public_key: "KEY".to_string(),
+                    location: "eu-west".to_string(),
+                    ip_address: "192.0.2.1".to_string(),
+                },
+            ),
+            ("omikron remove 3", Command::OmikronRemove { id: 3 }),
+            ("omikron cert 3", Command::OmikronCert { id: 3 }),
+            (
+                "omikron drain 3",
+                Command::OmikronDrain {
+                    id: 3,
+                    deadline_secs: None,
+                },
+            ),
+            (
+                "omikron drain 3 --deadline 60",
+                Command::OmikronDrain {
+                    id: 3,
+                    deadline_secs: Some(60),
+                },
+            ),
+            (
+                "user show alice",
+                Command::UserShow {
+                    query: "alice".to_string(),
+                },
+            ),
+            ("user delete 9", Command::UserDelete { id: 9 }),
+            ("help", Command::Help),
+            ("migrate --help", Command::Help),
+        ];
+        for (args, expected) in cases {
+            assert_eq!(command(args), Ok(expected), "{args}");
+        }
+    }
+
+    #[test]
+    fn reads_the_config_path() {
+        let cli = parse(["--config", "/etc/omega.toml", "migrate"].map(str::to_string)).unwrap();
+        assert_eq!(cli.config_path, Some(PathBuf::from("/etc/omega.toml")));
+        assert_eq!(cli.command, Command::Migrate);
+    }
+
+    #[test]
+    fn rejects_unknown_commands_and_flags() {
+        assert!(command("frobnicate").is_err());
+        assert!(command("omikron").is_err());
+        assert!(command("migrate --verbose").is_err());
+        assert!(command("user show").is_err());
+        assert!(command("omikron add KEY eu-west").is_err());
+    }
+
+    #[test]
+    fn rejects_missing_and_invalid_values() {
+        assert_eq!(
+            command("--config"),
+            Err("--config needs a file".to_string())
+        );
+        assert_eq!(
+            command("omikron drain 3 --deadline"),
+            Err("--deadline needs a value".to_string())
+        );
+        assert!(command("omikron add KEY eu-west 192.0.2.1 --id").is_err());
+        assert!(command("omikron drain 3 --deadline soon").is_err());
+        assert_eq!(
+            command("omikron remove x"),
+            Err("Invalid id: x".to_string())
+        );
+        assert!(command("user delete 0").is_err());
+        assert!(command("omikron cert -1").is_err());
+    }
+}
@@ -0,0 +1,729 @@
+use crate::util::{
+    crypto_helper::{load_public_key, load_secret_key},
+    file_util::get_directory,
+};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+pub const DEFAULT_CONFIG_FILE: &str = "omega.toml";
+
+// ============================================================================
+// Sections
+// ============================================================================
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: HttpsConfig,
+    pub transport: TransportConfig,
+    pub keys: KeysConfig,
+    pub database: DatabaseConfig,
+    pub short_link: ShortLinkConfig,
+    pub logger: LoggerConfig,
+    pub admin: AdminConfig,
+    pub selection: SelectionConfig,
+    pub presence: PresenceConfig,
+    pub health: HealthConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpsConfig {
+    pub bind: String,
+    pub port: u16,
+    pub cert: String,
+    pub key: String,
+    /// One sub directory per host name with `cert.pem` and `key.pem`.
+    pub sni_dir: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportConfig {
+    pub port: u16,
+    pub cert: String,
+    pub key: String,
+    /// Omikrons have to present a certificate issued by `client_ca_cert`
+    pub require_client_cert: bool,
+    pub client_ca_cert: String,
+    pub client_ca_key: String,
+    /// Omega pings an Omikron that has been silent for this long
+    pub heartbeat_interval_secs: u64,
+    /// Silent intervals after which the connection is considered dead
+    pub heartbeat_missed_limit: u32,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeysConfig {
+    /// Base64 encoded x448 secret key
+    pub private_key: String,
+    /// Base64 encoded x448 public key
+    pub public_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    pub name: String,
+    pub max_connections: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShortLinkConfig {
+    /// Prefix of generated short links, e.g. `https://omega.tensamin.net/direct`
+    pub base_url: String,
+    /// Redirect target for unknown short links
+    pub fallback_url: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggerConfig {
+    pub directory: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Plain HTTP, keep it on a loopback or private address. Also serves the
+    /// health probes, which need no token.
+    pub bind: String,
+    pub port: u16,
+    /// Bearer token for every admin request, the admin API is off while empty
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionStrategy {
+    /// Random, weighted towards Omikrons with fewer users and lower ping
+    #[default]
+    WeightedRandom,
+    /// The Omikron with the fewest users
+    LeastConnections,
+    /// Omikrons whose location matches the client region, then least connections
+    Region,
+}
+
+impl FromStr for SelectionStrategy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "weighted_random" => Ok(SelectionStrategy::WeightedRandom),
+            "least_connections" => Ok(SelectionStrategy::LeastConnections),
+            "region" => Ok(SelectionStrategy::Region),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelectionConfig {
+    pub strategy: SelectionStrategy,
+    /// Client network -> Omikron location, the longest matching prefix wins
+    pub ip_regions: BTreeMap<IpNetwork, String>,
+}
+
+/// An address with a prefix length, written as `10.1.0.0/16`, `2001:db8::/32`
+/// or a single address. The older `10.1.` form means whole octets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// IPv4-mapped IPv6 addresses are matched as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid network: {}", s);
+
+        if let Some(octets) = s.strip_suffix('.') {
+            let octets: Vec<u8> = octets
+                .split('.')
+                .map(|octet| octet.parse().map_err(|_| invalid()))
+                .collect::<Result<_, _>>()?;
+            if octets.is_empty() || octets.len() > 3 {
+                return Err(invalid());
+            }
+            let mut addr = [0u8; 4];
+            addr[..octets.len()].copy_from_slice(&octets);
+            return Ok(Self {
+                addr: IpAddr::from(addr),
+                prefix_len: octets.len() as u8 * 8,
+            });
+        }
+
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len.parse::<u8>().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| invalid())?
+            .to_canonical();
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl<'de> Deserialize<'de> for IpNetwork {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PresenceConfig {
+    /// Online users without activity for this long become idle, 0 turns it off.
+    /// Any message an Omikron sends with the user as sender is activity.
+    pub auto_idle_secs: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// `/readyz` fails while fewer Omikrons are authenticated, 0 turns it off
+    pub min_omikrons: usize,
+}
+
+impl Default for HttpsConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0".to_string(),
+            port: 9188,
+            cert: "certs/server_cert.pem".to_string(),
+            key: "certs/server_key.pem".to_string(),
+            sni_dir: Some("certs/sni".to_string()),
+        }
+    }
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            port: 9187,
+            cert: "certs/transport_cert.pem".to_string(),
+            key: "certs/transport_key.pem".to_string(),
+            require_client_cert: false,
+            client_ca_cert: "certs/omikron_ca.pem".to_string(),
+            client_ca_key: "certs/omikron_ca_key.pem".to_string(),
+            heartbeat_interval_secs: 15,
+            heartbeat_missed_limit: 3,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 3306,
+            username: String::new(),
+            password: String::new(),
+            name: String::new(),
+            max_connections: 200,
+        }
+    }
+}
+
+impl Default for ShortLinkConfig {
+    fn default() -> Self {
+        Self {
+            base_url: "https://omega.tensamin.net/direct".to_string(),
+            fallback_url: "https://tensamin.net".to_string(),
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1".to_string(),
+            port: 9189,
+            token: String::new(),
+        }
+    }
+}
+
+impl Default for LoggerConfig {
+    fn default() -> Self {
+        Self {
+            directory: "logs".to_string(),
+        }
+    }
+}
+
+// ============================================================================
+// Errors
+// ============================================================================
+
+/// Every problem found while loading the configuration.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} configuration problem(s):", self.problems.len())?;
+        for problem in &self.problems {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// ============================================================================
+// Loading
+// ============================================================================
+
+/// Parts of the configuration that are validated together. Management
+/// commands only validate what they use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    /// Ports, TLS certificates and the transport settings of `serve`
+    Listeners,
+    Keys,
+    Database,
+    Admin,
+    /// Short links and the logger
+    Misc,
+}
+
+impl Section {
+    pub const ALL: &[Section] = &[
+        Section::Listeners,
+        Section::Keys,
+        Section::Database,
+        Section::Admin,
+        Section::Misc,
+    ];
+}
+
+impl Config {
+    /// Reads `path` (if it exists), applies environment overrides and
+    /// validates the result.
+    pub fn load(path: Option<&Path>) -> Result<Config, ConfigError> {
+        Config::load_sections(path, Section::ALL)
+    }
+
+    /// Like `load`, but only validates `sections`.
+    pub fn load_sections(path: Option<&Path>, sections: &[Section]) -> Result<Config, ConfigError> {
+        let mut problems = Vec::new();
+
+        let path = path
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| resolve_path(DEFAULT_CONFIG_FILE));
+
+        let mut config = if path.exists() {
+            match fs::read_to_string(&path) {
+                Ok(content) => match toml::from_str::<Config>(&content) {
+                    Ok(config) => config,
+                    Err(e) => {
+                        problems.push(format!("{}: {}", path.display(), e));
+                        Config::default()
+                    }
+                },
+                Err(e) => {
+                    problems.push(format!("{}: {}", path.display(), e));
+                    Config::default()
+                }
+            }
+        } else {
+            Config::default()
+        };
+        // Validating the defaults would only bury the real error
+        if !problems.is_empty() {
+            return Err(ConfigError { problems });
+        }
+
+        config.apply_env(&mut problems);
+        config.validate(sections, &mut problems);
+
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError { problems })
+        }
+    }
+
+    fn apply_env(&mut self, problems: &mut Vec<String>) {
+        override_string(&mut self.server.bind, &["OMEGA_SERVER_BIND"]);
+        override_parsed(&mut self.server.port, &["OMEGA_SERVER_PORT"], problems);
+        override_string(&mut self.server.cert, &["OMEGA_SERVER_CERT"]);
+        override_string(&mut self.server.key, &["OMEGA_SERVER_KEY"]);
+        if let Some(dir) = env_value(&["OMEGA_SERVER_SNI_DIR"]) {
+            self.server.sni_dir = Some(dir).filter(|d| !d.is_empty());
+        }
+
+        override_parsed(
+            &mut self.transport.port,
+            &["OMEGA_TRANSPORT_PORT"],
+            problems,
+        );
+        override_string(&mut self.transport.cert, &["OMEGA_TRANSPORT_CERT"]);
+        override_string(&mut self.transport.key, &["OMEGA_TRANSPORT_KEY"]);
+        override_parsed(
+            &mut self.transport.require_client_cert,
+            &["OMEGA_TRANSPORT_REQUIRE_CLIENT_CERT"],
+            problems,
+        );
+        override_string(
+            &mut self.transport.client_ca_cert,
+            &["OMEGA_TRANSPORT_CLIENT_CA_CERT"],
+        );
+        override_string(
+            &mut self.transport.client_ca_key,
+            &["OMEGA_TRANSPORT_CLIENT_CA_KEY"],
+        );
+        override_parsed(
+            &mut self.transport.heartbeat_interval_secs,
+            &["OMEGA_TRANSPORT_HEARTBEAT_INTERVAL_SECS"],
+            problems,
+        );
+        override_parsed(
+            &mut self.transport.heartbeat_missed_limit,
+            &["OMEGA_TRANSPORT_HEARTBEAT_MISSED_LIMIT"],
+            problems,
+        );
+
+        // PRIVATE_KEY, PUBLIC_KEY and DB_* are the names used by older .env files
+        override_string(
+            &mut self.keys.private_key,
+            &["OMEGA_KEYS_PRIVATE_KEY", "PRIVATE_KEY"],
+        );
+        override_string(
+            &mut self.keys.public_key,
+            &["OMEGA_KEYS_PUBLIC_KEY", "PUBLIC_KEY"],
+        );
+
+        override_string(&mut self.database.host, &["OMEGA_DATABASE_HOST"]);
+        override_parsed(&mut self.database.port, &["OMEGA_DATABASE_PORT"], problems);
+        override_string(
+            &mut self.database.username,
+            &["OMEGA_DATABASE_USERNAME", "DB_USERNAME"],
+        );
+        override_string(
+            &mut self.database.password,
+            &["OMEGA_DATABASE_PASSWORD", "DB_PASSWD"],
+        );
+        override_string(
+            &mut self.database.name,
+            &["OMEGA_DATABASE_NAME", "DB_TABLE"],
+        );
+        override_parsed(
+            &mut self.database.max_connections,
+            &["OMEGA_DATABASE_MAX_CONNECTIONS"],
+            problems,
+        );
+
+        override_string(
+            &mut self.short_link.base_url,
+            &["OMEGA_SHORT_LINK_BASE_URL"],
+        );
+        override_string(
+            &mut self.short_link.fallback_url,
+            &["OMEGA_SHORT_LINK_FALLBACK_URL"],
+        );
+
+        override_string(&mut self.logger.directory, &["OMEGA_LOGGER_DIRECTORY"]);
+
+        override_string(&mut self.admin.bind, &["OMEGA_ADMIN_BIND"]);
+        override_parsed(&mut self.admin.port, &["OMEGA_ADMIN_PORT"], problems);
+        override_string(&mut self.admin.token, &["OMEGA_ADMIN_TOKEN"]);
+
+        override_parsed(
+            &mut self.selection.strategy,
+            &["OMEGA_SELECTION_STRATEGY"],
+            problems,
+        );
+
+        override_parsed(
+            &mut self.presence.auto_idle_secs,
+            &["OMEGA_PRESENCE_AUTO_IDLE_SECS"],
+            problems,
+        );
+
+        override_parsed(
+            &mut self.health.min_omikrons,
+            &["OMEGA_HEALTH_MIN_OMIKRONS"],
+            problems,
+        );
+    }
+
+    fn validate(&self, sections: &[Section], problems: &mut Vec<String>) {
+        for section in sections {
+            match section {
+                Section::Listeners => self.validate_listeners(problems),
+                Section::Keys => self.validate_keys(problems),
+                Section::Database => self.validate_database(problems),
+                Section::Admin => self.validate_admin(problems),
+                Section::Misc => self.validate_misc(problems),
+            }
+        }
+    }
+
+    fn validate_listeners(&self, problems: &mut Vec<String>) {
+        if self.server.port == 0 {
+            problems.push("server.port must not be 0".to_string());
+        }
+        if self.transport.port == 0 {
+            problems.push("transport.port must not be 0".to_string());
+        }
+        if self.transport.heartbeat_interval_secs == 0 {
+            problems.push("transport.heartbeat_interval_secs must not be 0".to_string());
+        }
+        if self.transport.heartbeat_missed_limit == 0 {
+            problems.push("transport.heartbeat_missed_limit must not be 0".to_string());
+        }
+        if self.server.port != 0 && self.server.port == self.transport.port {
+            problems.push(format!(
+                "server.port and transport.port are both {}",
+                self.server.port
+            ));
+        }
+        for (name, port) in [
+            ("server.port", self.server.port),
+            ("transport.port", self.transport.port),
+        ] {
+            if self.admin.port == port {
+                problems.push(format!("admin.port and {} are both {}", name, port));
+            }
+        }
+
+        for (name, path) in [
+            ("server.cert", &self.server.cert),
+            ("server.key", &self.server.key),
+            ("transport.cert", &self.transport.cert),
+            ("transport.key", &self.transport.key),
+        ] {
+            if !resolve_path(path).is_file() {
+                problems.push(format!(
+                    "{} not found: {}",
+                    name,
+                    resolve_path(path).display()
+                ));
+            }
+        }
+        if self.transport.require_client_cert
+            && !resolve_path(&self.transport.client_ca_cert).is_file()
+        {
+            problems.push(format!(
+                "transport.require_client_cert is set but transport.client_ca_cert was not found: {} (run `omega ca init`)",
+                resolve_path(&self.transport.client_ca_cert).display()
+            ));
+        }
+    }
+
+    fn validate_keys(&self, problems: &mut Vec<String>) {
+        match (
+            self.keys.private_key.is_empty(),
+            self.keys.public_key.is_empty(),
+        ) {
+            (true, _) => problems.push("keys.private_key is not set".to_string()),
+            (_, true) => problems.push("keys.public_key is not set".to_string()),
+            _ => {
+                let secret = load_secret_key(&self.keys.private_key);
+                let public = load_public_key(&self.keys.public_key);
+                match (secret, public) {
+                    (None, _) => {
+                        problems.push("keys.private_key is not a valid x448 key".to_string())
+                    }
+                    (_, None) => {
+                        problems.push("keys.public_key is not a valid x448 key".to_string())
+                    }
+                    (Some(secret), Some(public)) => {
+                        if x448::PublicKey::from(&secret).as_bytes() != public.as_bytes() {
+                            problems.push(
+                                "keys.public_key does not belong to keys.private_key".to_string(),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn validate_database(&self, problems: &mut Vec<String>) {
+        if self.database.username.is_empty() {
+            problems.push("database.username is not set".to_string());
+        }
+        if self.database.name.is_empty() {
+            problems.push("database.name is not set".to_string());
+        }
+        if self.database.max_connections == 0 {
+            problems.push("database.max_connections must be at least 1".to_string());
+        }
+    }
+
+    fn validate_admin(&self, problems: &mut Vec<String>) {
+        if self.admin.port == 0 {
+            problems.push("admin.port must not be 0".to_string());
+        }
+        if self.admin.enabled() && self.admin.token.len() < 16 {
+            problems.push("admin.token must be at least 16 characters".to_string());
+        }
+    }
+
+    fn validate_misc(&self, problems: &mut Vec<String>) {
+        for (name, url) in [
+            ("short_link.base_url", &self.short_link.base_url),
+            ("short_link.fallback_url", &self.short_link.fallback_url),
+        ] {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                problems.push(format!("{} must be an http(s) URL: {}", name, url));
+            }
+        }
+
+        if self.logger.directory.is_empty() {
+            problems.push("logger.directory must not be empty".to_string());
+        }
+    }
+}
+
+impl AdminConfig {
+    pub fn enabled(&self) -> bool {
+        !self.token.is_empty()
+    }
+}
+
+fn env_value(names: &[&str]) -> Option<String> {
+    names.iter().find_map(|name| env::var(name).ok())
+}
+
+fn override_string(target: &mut String, names: &[&str]) {
+    if let Some(value) = env_value(names) {
+        *target = value;
+    }
+}
+
+fn override_parsed<T: FromStr>(target: &mut T, names: &[&str], problems: &mut Vec<String>) {
+    if let Some(value) = env_value(names) {
+        match value.parse() {
+            Ok(parsed) => *target = parsed,
+            Err(_) => problems.push(format!("{} has an invalid value: {}", names[0], value)),
+        }
+    }
+}
+
+/// Relative paths in the configuration are relative to the Omega directory.
+pub fn resolve_path(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        Path::new(&get_directory()).join(path)
+    }
+}
+
+// ============================================================================
+// Global Access
+// ============================================================================
+
+pub fn init(config: Config) {
+    CONFIG.set(config).expect("Config already initialized");
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("Config not initialized")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(s: &str) -> IpNetwork {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn networks_match_whole_prefixes() {
+        let net = network("10.1.0.0/16");
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(!net.contains(ip("10.100.2.3")));
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("2001:db8::1")));
+
+        assert!(network("2001:db8::/32").contains(ip("2001:db8:1::1")));
+        assert!(network("0.0.0.0/0").contains(ip("192.0.2.1")));
+        assert!(network("192.0.2.1").contains(ip("192.0.2.1")));
+        assert!(!network("192.0.2.1").contains(ip("192.0.2.2")));
+    }
+
+    #[test]
+    fn octet_prefixes_are_networks() {
+        assert_eq!(network("10.1."), network("10.1.0.0/16"));
+        assert!(!network("10.1.").contains(ip("10.100.0.1")));
+    }
+
+    #[test]
+    fn malformed_networks_are_rejected() {
+        for s in [
+            "10.1",
+            "10.0.0.0/33",
+            "::/129",
+            "10.256.",
+            "1.2.3.4.",
+            "eu",
+            "",
+        ] {
+            assert!(s.parse::<IpNetwork>().is_err(), "{s}");
+        }
+    }
+}
@@ -1,65 +1,145 @@
+mod cli;
+mod config;
 mod server;
 mod sql;
 mod transport;
 mod util;
 
+use crate::cli::{self as omega_cli, Command, USAGE};
+use crate::config::Config;
+use crate::server::health::{self, Listener, ListenerState};
+use crate::sql::sql::close as close_db;
 use crate::sql::sql::initialize_db;
-use crate::sql::sql::print_users;
-use crate::transport::omikron_connection;
+use crate::sql::sql::migrate;
+use crate::sql::{presence_store, user_online_tracker};
+use crate::transport::{omikron_connection, omikron_manager};
 use crate::util::crypto_helper::load_public_key;
 use crate::util::crypto_helper::load_secret_key;
 use crate::util::logger::PrintType;
-use crate::util::logger::startup;
+use crate::util::logger::{flush, startup};
+use crate::util::shutdown;
 use dotenv::dotenv;
-use once_cell::sync::Lazy;
 use rustls::crypto::aws_lc_rs::default_provider;
-use std::env;
+use std::{env, path::Path, process::ExitCode, time::Duration};
 
-static PRIVATE_KEY: Lazy<String> = Lazy::new(|| env::var("PRIVATE_KEY").unwrap());
+// Both keys are validated when the config is loaded
 pub fn get_private_key() -> x448::Secret {
-    load_secret_key(&*PRIVATE_KEY).unwrap()
+    load_secret_key(&config::get().keys.private_key).expect("private key validated at startup")
 }
-static PUBLIC_KEY: Lazy<String> = Lazy::new(|| env::var("PUBLIC_KEY").unwrap());
 pub fn get_public_key() -> x448::PublicKey {
-    load_public_key(&*PUBLIC_KEY).unwrap()
+    load_public_key(&config::get().keys.public_key).expect("public key validated at startup")
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     if let Err(_) = default_provider().install_default() {
         println!("Error loading Provider");
-        return;
+        return ExitCode::FAILURE;
     }
     dotenv().ok();
-    startup();
+
+    let cli = match omega_cli::parse(env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    if cli.command != Command::Serve {
+        return match omega_cli::run(cli).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("Error: {:#}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    serve(cli.config_path.as_deref()).await
+}
+
+async fn serve(config_path: Option<&Path>) -> ExitCode {
+    let config = match Config::load(config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("[FATAL] {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    config::init(config);
+    let config = config::get();
+
+    startup(&config.logger);
     log_in!("Incoming messages");
     log_out!("Outgoing messages");
+    shutdown::listen_for_signals();
 
     tokio::spawn(async move {
-        match omikron_connection::start(9187).await {
-            Err(e) => log_err!(0, PrintType::General, "{:?}", e),
-            _ => {}
+        if let Err(e) = omikron_connection::start(&config.transport).await {
+            log_err!(0, PrintType::General, "{:?}", e);
+        }
+        // Reported by /healthz, the process is not usable without it
+        if !shutdown::is_shutting_down() {
+            health::set_listener(Listener::Epsilon, ListenerState::Failed);
         }
     });
 
     log!("Started");
-    log!("  .env");
-    if let Err(e) = initialize_db().await {
+    log!("  Config");
+    if let Err(e) = initialize_db(&config.database).await {
         log!("[FATAL] Database initialization failed: {}", e);
         log!(
-            "[FATAL] Please ensure the database is running and the .env file is configured correctly."
+            "[FATAL] Please ensure the database is running and the database section of the config is correct."
         );
-        return;
+        return ExitCode::FAILURE;
     } else {
         log!("  DB");
     }
-    if let Err(e) = print_users().await {
-        log!("[ERROR] Failed to print users: {}", e);
-    } else {
-        log!("  Users");
+    if let Err(e) = migrate().await {
+        log!("[FATAL] Database migration failed: {}", e);
+        return ExitCode::FAILURE;
+    }
+    if let Err(e) = presence_store::start().await {
+        log!("[FATAL] Loading presence settings failed: {}", e);
+        return ExitCode::FAILURE;
+    }
+
+    if config.presence.auto_idle_secs > 0 {
+        user_online_tracker::start_auto_idle(Duration::from_secs(config.presence.auto_idle_secs));
     }
 
-    let _ = server::server::start(9188).await;
+    match server::server::start_admin(&config.admin) {
+        Ok(admin_server) => {
+            tokio::spawn(admin_server);
+        }
+        Err(e) => log!("[ERROR] Admin listener failed to start: {}", e),
+    }
+    if !config.admin.enabled() {
+        log!("  Admin API disabled (admin.token is not set)");
+    }
+
+    // Returns once shutdown was triggered and running requests are done
+    let result = server::server::start(&config.server).await;
+    if let Err(e) = &result {
+        log!("[FATAL] HTTPS server failed to start: {}", e);
+    }
+    shut_down().await;
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(_) => ExitCode::FAILURE,
+    }
+}
 
-    tokio::signal::ctrl_c().await.unwrap();
+async fn shut_down() {
+    shutdown::trigger();
+    log!("Shutting down");
+    omikron_manager::shutdown(shutdown::GRACE).await;
+    // Connection cleanups record presence until here
+    presence_store::flush().await;
+    close_db().await;
+    log!("Stopped");
+    // Waits on the logger thread, so keep it off the runtime's workers
+    let _ = tokio::task::spawn_blocking(|| flush(Duration::from_secs(2))).await;
 }
@@ -0,0 +1,339 @@
+use crate::config;
+use crate::sql::{sql, user_online_tracker::StatusCounts};
+use crate::transport::{
+    omikron_connection::{DEFAULT_REQUEST_TIMEOUT, OmikronError, OmikronStats},
+    omikron_manager,
+};
+use crate::util::crypto_helper::load_public_key;
+use actix_web::HttpResponse;
+use actix_web::http::{Method, StatusCode, header};
+use epsilon_core::{CommunicationType, CommunicationValue};
+use json::JsonValue;
+use std::time::{Duration, Instant};
+
+/// How long the replaced key of a rotated Omikron keeps working.
+const DEFAULT_ROTATION_OVERLAP_SECS: u64 = 24 * 60 * 60;
+/// How long a draining Omikron gets to move its clients.
+pub const DEFAULT_DRAIN_SECS: u64 = 5 * 60;
+
+/// Checks an `Authorization: Bearer <token>` header against `admin.token`.
+pub fn authorized(authorization: Option<&str>) -> bool {
+    let expected = config::get().admin.token.as_bytes();
+    let Some(given) = authorization.and_then(|h| h.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    let given = given.as_bytes();
+    if expected.is_empty() {
+        return false;
+    }
+
+    // Compare every byte so the time taken does not leak the matching prefix
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+pub async fn handle(method: &Method, path: &str, body_string: Option<String>) -> HttpResponse {
+    let path_parts: Vec<&str> = path.split("/").filter(|s| !s.is_empty()).collect();
+
+    let body: JsonValue = body_string
+        .and_then(|b| json::parse(&b).ok())
+        .unwrap_or(JsonValue::Null);
+
+    let (status, body_text) = match (method.as_str(), path_parts.as_slice()) {
+        // ==================================================
+        // NETWORK TOPOLOGY
+        // ==================================================
+        ("GET", ["admin", "topology"]) => {
+            let topology = omikron_manager::topology();
+
+            let mut omikrons = JsonValue::new_array();
+            for omikron in &topology.omikrons {
+                let mut entry = JsonValue::new_object();
+                entry["id"] = omikron.id.into();
+                entry["connected"] = omikron.connected_for.is_some().into();
+                entry["connected_secs"] = match omikron.connected_for {
+                    Some(age) => age.as_secs().into(),
+                    None => JsonValue::Null,
+                };
+                entry["draining"] = omikron.draining.into();
+                entry["users"] = status_counts_json(&omikron.counts.users_by_status);
+                entry["primary_iotas"] = omikron.counts.primary_iotas.into();
+                entry["secondary_iotas"] = omikron.counts.secondary_iotas.into();
+                let _ = omikrons.push(entry);
+            }
+
+            let mut res = JsonValue::new_object();
+            res["status"] = "success".into();
+            res["users"] = status_counts_json(&topology.users_by_status);
+            res["iotas"] = topology.iotas.into();
+            res["omikrons"] = omikrons;
+            (StatusCode::OK, res.dump())
+        }
+
+        // ==================================================
+        // LIST OMIKRONS
+        // ==================================================
+        ("GET", ["admin", "omikrons"]) => match sql::get_omikrons().await {
+            Ok(omikrons) => {
+                let mut list = JsonValue::new_array();
+                for (id, public_key, location, ip_address) in omikrons {
+                    let mut entry = JsonValue::new_object();
+                    entry["id"] = id.into();
+                    entry["public_key"] = public_key.into();
+                    entry["location"] = location.into();
+                    entry["ip_address"] = ip_address.into();
+                    entry["connected"] = omikron_manager::is_connected(id).into();
+                    let _ = list.push(entry);
+                }
+                let mut res = JsonValue::new_object();
+                res["status"] = "success".into();
+                res["omikrons"] = list;
+                (StatusCode::OK, res.dump())
+            }
+            Err(_) => status_only(StatusCode::INTERNAL_SERVER_ERROR, "error"),
+        },
+
+        // ==================================================
+        // REGISTER OMIKRON
+        // ==================================================
+        ("POST", ["admin", "omikrons"]) => {
+            match (
+                body["public_key"].as_str(),
+                body["location"].as_str(),
+                body["ip_address"].as_str(),
+            ) {
+                (Some(public_key), Some(location), Some(ip_address))
+                    if load_public_key(public_key).is_some() =>
+                {
+                    match sql::create_omikron(
+                        public_key.to_string(),
+                        location.to_string(),
+                        ip_address.to_string(),
+                    )
+                    .await
+                    {
+                        Ok(id) => {
+                            let mut res = JsonValue::new_object();
+                            res["status"] = "success".into();
+                            res["id"] = id.into();
+                            (StatusCode::OK, res.dump())
+                        }
+                        Err(_) => status_only(StatusCode::INTERNAL_SERVER_ERROR, "error"),
+                    }
+                }
+                _ => status_only(StatusCode::BAD_REQUEST, "error_bad_request"),
+            }
+        }
+
+        // ==================================================
+        // GET OMIKRON
+        // ==================================================
+        ("GET", ["admin", "omikrons", id]) => match parse_id(id) {
+            Some(id) => match sql::get_omikron_record(id).await {
+                Ok((public_key, previous_key, location, ip_address)) => {
+                    let mut res = JsonValue::new_object();
+                    res["status"] = "success".into();
+                    res["id"] = id.into();
+                    res["public_key"] = public_key.into();
+                    if let Some(previous_key) = previous_key {
+                        res["previous_public_key"] = previous_key.into();
+                    }
+                    res["location"] = location.into();
+                    res["ip_address"] = ip_address.into();
+                    res["connected"] = omikron_manager::is_connected(id).into();
+                    (StatusCode::OK, res.dump())
+                }
+                Err(_) => status_only(StatusCode::NOT_FOUND, "error_not_found"),
+            },
+            None => status_only(StatusCode::BAD_REQUEST, "error_bad_request"),
+        },
+
+        // ==================================================
+        // OMIKRON STATS
+        // ==================================================
+        ("GET", ["admin", "omikrons", id, "stats"]) => {
+            match parse_id(id).and_then(omikron_manager::get_connection) {
+                Some(conn) => {
+                    let mut res = JsonValue::new_object();
+                    res["status"] = "success".into();
+                    res["draining"] = conn.is_draining().into();
+                    res["healthy"] = conn.is_healthy().await.into();
+                    res["ping"] = conn.get_ping().await.into();
+                    res["stats"] = match conn.get_stats().await {
+                        Some(stats) => stats_json(&stats),
+                        None => JsonValue::Null,
+                    };
+                    (StatusCode::OK, res.dump())
+                }
+                None => status_only(StatusCode::NOT_FOUND, "error_not_found"),
+            }
+        }
+
+        // ==================================================
+        // PING OMIKRON
+        // ==================================================
+        ("GET", ["admin", "omikrons", id, "ping"]) => {
+            match parse_id(id).and_then(omikron_manager::get_connection) {
+                Some(conn) => {
+                    let started = Instant::now();
+                    let ping = CommunicationValue::new(CommunicationType::ping);
+                    match conn.request(ping, DEFAULT_REQUEST_TIMEOUT).await {
+                        Ok(_) => {
+                            let mut res = JsonValue::new_object();
+                            res["status"] = "success".into();
+                            res["round_trip_ms"] = (started.elapsed().as_millis() as u64).into();
+                            (StatusCode::OK, res.dump())
+                        }
+                        Err(OmikronError::Timeout) => {
+                            status_only(StatusCode::GATEWAY_TIMEOUT, "error_timeout")
+                        }
+                        Err(_) => status_only(StatusCode::BAD_GATEWAY, "error"),
+                    }
+                }
+                None => status_only(StatusCode::NOT_FOUND, "error_not_found"),
+            }
+        }
+
+        // ==================================================
+        // ROTATE OMIKRON KEY
+        // ==================================================
+        ("POST", ["admin", "omikrons", id, "rotate_key"]) => {
+            let overlap = body["overlap_secs"]
+                .as_u64()
+                .unwrap_or(DEFAULT_ROTATION_OVERLAP_SECS);
+
+            match (parse_id(id), body["public_key"].as_str()) {
+                (Some(id), Some(public_key)) if load_public_key(public_key).is_some() => {
+                    match sql::rotate_omikron_key(id, public_key.to_string(), overlap).await {
+                        Ok(true) => status_only(StatusCode::OK, "success"),
+                        Ok(false) => status_only(StatusCode::NOT_FOUND, "error_not_found"),
+                        Err(_) => status_only(StatusCode::INTERNAL_SERVER_ERROR, "error"),
+                    }
+                }
+                _ => status_only(StatusCode::BAD_REQUEST, "error_bad_request"),
+            }
+        }
+
+        // ==================================================
+        // UPDATE OMIKRON LOCATION / ADDRESS
+        // ==================================================
+        ("POST", ["admin", "omikrons", id, "update"]) => {
+            let location = body["location"].as_str().map(str::to_string);
+            let ip_address = body["ip_address"].as_str().map(str::to_string);
+
+            match parse_id(id) {
+                Some(id) if location.is_some() || ip_address.is_some() => {
+                    match sql::update_omikron_address(id, location.clone(), ip_address).await {
+                        Ok(true) => {
+                            if let Some(location) = location {
+                                omikron_manager::update_location(id, location).await;
+                            }
+                            status_only(StatusCode::OK, "success")
+                        }
+                        Ok(false) => status_only(StatusCode::NOT_FOUND, "error_not_found"),
+                        Err(_) => status_only(StatusCode::INTERNAL_SERVER_ERROR, "error"),
+                    }
+                }
+                _ => status_only(StatusCode::BAD_REQUEST, "error_bad_request"),
+            }
+        }
+
+        // ==================================================
+        // MARK OMIKRON AS DRAINING
+        // ==================================================
+        ("POST", ["admin", "omikrons", id, "draining"]) => {
+            let draining = body["draining"].as_bool().unwrap_or(true);
+            match parse_id(id) {
+                Some(id) if omikron_manager::set_draining(id, draining) => {
+                    status_only(StatusCode::OK, "success")
+                }
+                Some(_) => status_only(StatusCode::NOT_FOUND, "error_not_found"),
+                None => status_only(StatusCode::BAD_REQUEST, "error_bad_request"),
+            }
+        }
+
+        // ==================================================
+        // DRAIN OMIKRON
+        // ==================================================
+        ("POST", ["admin", "omikrons", id, "drain"]) => {
+            let grace = body["deadline_secs"].as_u64().unwrap_or(DEFAULT_DRAIN_SECS);
+            match parse_id(id) {
+                Some(id) => {
+                    match omikron_manager::drain_omikron(id, Duration::from_secs(grace)).await {
+                        Some(deadline) => {
+                            let mut res = JsonValue::new_object();
+                            res["status"] = "success".into();
+                            res["deadline"] = deadline.into();
+                            (StatusCode::OK, res.dump())
+                        }
+                        None => status_only(StatusCode::NOT_FOUND, "error_not_found"),
+                    }
+                }
+                None => status_only(StatusCode::BAD_REQUEST, "error_bad_request"),
+            }
+        }
+
+        // ==================================================
+        // DECOMMISSION OMIKRON
+        // ==================================================
+        ("POST", ["admin", "omikrons", id, "decommission"]) => match parse_id(id) {
+            Some(id) => match sql::delete_omikron(id).await {
+                Ok(true) => {
+                    let was_connected = omikron_manager::disconnect_omikron(id).await;
+                    let mut res = JsonValue::new_object();
+                    res["status"] = "success".into();
+                    res["disconnected"] = was_connected.into();
+                    (StatusCode::OK, res.dump())
+                }
+                Ok(false) => status_only(StatusCode::NOT_FOUND, "error_not_found"),
+                Err(_) => status_only(StatusCode::INTERNAL_SERVER_ERROR, "error"),
+            },
+            None => status_only(StatusCode::BAD_REQUEST, "error_bad_request"),
+        },
+
+        // ==================================================
+        // DEFAULT
+        // ==================================================
+        _ => status_only(StatusCode::NOT_FOUND, "error_not_found"),
+    };
+
+    HttpResponse::build(status)
+        .insert_header((header::CONTENT_TYPE, "application/json"))
+        .body(body_text)
+}
+
+fn stats_json(stats: &OmikronStats) -> JsonValue {
+    let mut res = JsonValue::new_object();
+    res["clients"] = stats.clients.into();
+    res["iotas"] = stats.iotas.into();
+    res["cpu_usage"] = stats.cpu_usage.into();
+    res["memory_usage"] = stats.memory_usage.into();
+    res["version"] = stats.version.as_str().into();
+    res["age_secs"] = stats.received_at.elapsed().as_secs().into();
+    res["stale"] = stats.is_stale().into();
+    res
+}
+
+/// `{"total": n, "user_online": n, ...}`
+fn status_counts_json(counts: &StatusCounts) -> JsonValue {
+    let mut res = JsonValue::new_object();
+    res["total"] = counts.values().sum::<usize>().into();
+    for (status, count) in counts {
+        res[status.as_str()] = (*count).into();
+    }
+    res
+}
+
+fn status_only(status: StatusCode, text: &str) -> (StatusCode, String) {
+    let mut res = JsonValue::new_object();
+    res["status"] = text.into();
+    (status, res.dump())
+}
+
+fn parse_id(id: &str) -> Option<i64> {
+    id.parse::<i64>().ok().filter(|id| *id > 0)
+}
@@ -1,18 +1,26 @@
 use crate::get_public_key;
+use crate::sql::connection_status::UserStatus;
+use crate::sql::presence_store;
 use crate::sql::sql;
-use crate::sql::user_online_tracker::get_iota_primary_omikron_connection;
-use crate::transport::omikron_manager::get_random_omikron;
+use crate::sql::user_online_tracker;
+use crate::transport::omikron_manager::{self, SelectionHint, select_omikron};
 use crate::util::file_util::get_directory;
 use crate::{
     sql::sql::{get_by_user_id, get_omikron_by_id},
     util::crypto_helper::public_key_to_base64,
 };
-use actix_web::HttpResponse;
 use actix_web::http::{StatusCode, header};
+use actix_web::{HttpResponse, web};
 use base64::Engine as _;
 use json::JsonValue;
+use std::collections::HashMap;
 
-pub async fn handle(path: &str, body_string: Option<String>) -> HttpResponse {
+pub async fn handle(
+    path: &str,
+    query: &str,
+    client_ip: Option<String>,
+    body_string: Option<String>,
+) -> HttpResponse {
     if path == "OPTIONS" {
         return HttpResponse::Ok()
             .insert_header(("Access-Control-Allow-Origin", "*"))
@@ -65,7 +73,11 @@ pub async fn handle(path: &str, body_string: Option<String>) -> HttpResponse {
         // GET RANDOM OMIKRON
         // ==================================================
         ["api", "get", "omikron"] => {
-            if let Ok(omikron_conn) = get_random_omikron().await {
+            let hint = SelectionHint {
+                region: query_param(query, "region"),
+                client_ip,
+            };
+            if let Ok(omikron_conn) = select_omikron(&hint).await {
                 if let Some(id) = omikron_conn.get_omikron_id().await {
                     if let Ok((public_key, ip_address)) = sql::get_omikron_by_id(id).await {
                         let mut res = JsonValue::new_object();
@@ -109,7 +121,7 @@ pub async fn handle(path: &str, body_string: Option<String>) -> HttpResponse {
                 res["public_key"] = public_key.into();
                 res["ip_address"] = ip_address.into();
                 (StatusCode::OK, res.dump())
-            } else if let Some(omikron_id) = get_iota_primary_omikron_connection(id) {
+            } else if let Some(omikron_id) = omikron_manager::live_primary(id) {
                 if let Ok((public_key, ip_address)) = get_omikron_by_id(omikron_id).await {
                     let mut res = JsonValue::new_object();
                     res["status"] = "success".into();
@@ -124,7 +136,7 @@ pub async fn handle(path: &str, body_string: Option<String>) -> HttpResponse {
                 }
             } else if let Ok((_, iota_id, _, _, _, _, _, _, _, _, _, _)) = get_by_user_id(id).await
             {
-                if let Some(omikron_id) = get_iota_primary_omikron_connection(iota_id) {
+                if let Some(omikron_id) = omikron_manager::live_primary(iota_id) {
                     if let Ok((public_key, ip_address)) = get_omikron_by_id(omikron_id).await {
                         let mut res = JsonValue::new_object();
                         res["status"] = "success".into();
@@ -248,6 +260,14 @@ pub async fn handle(path: &str, body_string: Option<String>) -> HttpResponse {
                         .into();
                 }
 
+                res["online_status"] = match user_online_tracker::get_user_status(id) {
+                    Some(user_status) => user_status.connection_type.to_string().into(),
+                    None => UserStatus::iota_offline.to_string().into(),
+                };
+                if let Some(last_seen) = presence_store::last_seen(id).await {
+                    res["last_seen"] = last_seen.into();
+                }
+
                 (StatusCode::OK, res.dump())
             } else {
                 let mut res = JsonValue::new_object();
@@ -273,3 +293,30 @@ pub async fn handle(path: &str, body_string: Option<String>) -> HttpResponse {
         .insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, "GET, POST, OPTIONS"))
         .body(body_bytes)
 }
+
+/// The percent-decoded value of a query parameter.
+fn query_param(query: &str, name: &str) -> Option<String> {
+    web::Query::<HashMap<String, String>>::from_query(query)
+        .ok()?
+        .into_inner()
+        .remove(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_values_are_decoded() {
+        assert_eq!(
+            query_param("a=1&region=eu%2Dwest", "region").as_deref(),
+            Some("eu-west")
+        );
+        assert_eq!(
+            query_param("region=eu+west", "region").as_deref(),
+            Some("eu west")
+        );
+        assert_eq!(query_param("a=1", "region"), None);
+        assert_eq!(query_param("", "region"), None);
+    }
+}
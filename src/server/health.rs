@@ -0,0 +1,105 @@
+use crate::config;
+use crate::sql::sql;
+use crate::transport::omikron_manager;
+use crate::util::{
+    crypto_helper::{load_public_key, load_secret_key},
+    shutdown,
+};
+use json::JsonValue;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+
+/// How long the database gets to answer a readiness check.
+const DB_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ListenerState {
+    Starting,
+    Bound,
+    /// Was started and stopped or failed to bind
+    Failed,
+}
+
+#[derive(Clone, Copy)]
+pub enum Listener {
+    Https,
+    Epsilon,
+}
+
+static HTTPS: AtomicU8 = AtomicU8::new(ListenerState::Starting as u8);
+static EPSILON: AtomicU8 = AtomicU8::new(ListenerState::Starting as u8);
+
+fn slot(listener: Listener) -> &'static AtomicU8 {
+    match listener {
+        Listener::Https => &HTTPS,
+        Listener::Epsilon => &EPSILON,
+    }
+}
+
+pub fn set_listener(listener: Listener, state: ListenerState) {
+    slot(listener).store(state as u8, Ordering::Relaxed);
+}
+
+fn listener_state(listener: Listener) -> ListenerState {
+    match slot(listener).load(Ordering::Relaxed) {
+        0 => ListenerState::Starting,
+        1 => ListenerState::Bound,
+        _ => ListenerState::Failed,
+    }
+}
+
+fn listener_json(listener: Listener) -> JsonValue {
+    match listener_state(listener) {
+        ListenerState::Starting => "starting",
+        ListenerState::Bound => "bound",
+        ListenerState::Failed => "failed",
+    }
+    .into()
+}
+
+/// Liveness fails once a listener has died, the process cannot recover from
+/// that on its own.
+pub fn live() -> (bool, JsonValue) {
+    let live = [Listener::Https, Listener::Epsilon]
+        .into_iter()
+        .all(|listener| listener_state(listener) != ListenerState::Failed);
+
+    let mut res = JsonValue::new_object();
+    res["status"] = if live { "ok" } else { "failed" }.into();
+    res["https"] = listener_json(Listener::Https);
+    res["epsilon"] = listener_json(Listener::Epsilon);
+    (live, res)
+}
+
+/// Readiness needs the database, both listeners, the keys and, if
+/// configured, a minimum number of authenticated Omikrons. It fails as soon
+/// as shutdown starts.
+pub async fn ready() -> (bool, JsonValue) {
+    let config = config::get();
+
+    let database = matches!(
+        tokio::time::timeout(DB_CHECK_TIMEOUT, sql::ping()).await,
+        Ok(true)
+    );
+    let https = listener_state(Listener::Https) == ListenerState::Bound;
+    let epsilon = listener_state(Listener::Epsilon) == ListenerState::Bound;
+    let keys = load_secret_key(&config.keys.private_key).is_some()
+        && load_public_key(&config.keys.public_key).is_some();
+    let omikrons = omikron_manager::OMIKRON_CONNECTIONS.len();
+    let enough_omikrons = omikrons >= config.health.min_omikrons;
+
+    let shutting_down = shutdown::is_shutting_down();
+
+    let ready = database && https && epsilon && keys && enough_omikrons && !shutting_down;
+
+    let mut res = JsonValue::new_object();
+    res["status"] = if ready { "ok" } else { "not_ready" }.into();
+    res["database"] = database.into();
+    res["https"] = listener_json(Listener::Https);
+    res["epsilon"] = listener_json(Listener::Epsilon);
+    res["keys"] = keys.into();
+    res["omikrons"] = omikrons.into();
+    res["min_omikrons"] = config.health.min_omikrons.into();
+    res["shutting_down"] = shutting_down.into();
+    (ready, res)
+}
@@ -1,3 +1,6 @@
+pub mod admin;
 pub mod api;
+pub mod health;
 pub mod server;
 pub mod short_link;
+pub mod tls;
@@ -1,67 +1,244 @@
 use crate::{
+    config::{self, AdminConfig, HttpsConfig, resolve_path},
     log,
-    server::{api, short_link::get_short_link},
-    util::file_util::load_file_buf,
+    server::{
+        admin, api,
+        health::{self, Listener, ListenerState},
+        short_link::get_short_link,
+        tls::build_resolver,
+    },
+    util::{metrics, shutdown},
 };
 
-use actix_web::{App, HttpRequest, HttpResponse, HttpServer, Responder, http::header, web};
+use actix_web::{
+    App, HttpRequest, HttpResponse, HttpServer, Responder, dev::Server, http::header, web,
+};
 
 use rustls::ServerConfig;
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
-use rustls_pemfile::{certs, pkcs8_private_keys};
-
-pub async fn start(port: u16) -> anyhow::Result<()> {
-    let mut cert_reader = load_file_buf("certs", "server_cert.pem")?;
-
-    let mut key_reader = load_file_buf("certs", "server_key.pem")?;
+use std::{sync::Arc, time::Instant};
 
-    let cert_chain: Vec<CertificateDer<'static>> =
-        certs(&mut cert_reader).collect::<Result<_, _>>()?;
-
-    let mut keys: Vec<PrivateKeyDer<'static>> = pkcs8_private_keys(&mut key_reader)
-        .map(|res| res.map(Into::into))
-        .collect::<Result<_, _>>()?;
-
-    let key = keys.remove(0);
+pub async fn start(server_config: &HttpsConfig) -> anyhow::Result<()> {
+    let sni_dir = server_config.sni_dir.as_deref().map(resolve_path);
+    let resolver = build_resolver(
+        &resolve_path(&server_config.cert),
+        &resolve_path(&server_config.key),
+        sni_dir.as_deref(),
+    )?;
 
     let mut config = ServerConfig::builder()
         .with_no_client_auth()
-        .with_single_cert(cert_chain, key)?;
+        .with_cert_resolver(Arc::new(resolver));
 
     config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
 
-    let addr = format!("0.0.0.0:{port}");
+    let addr = format!("{}:{}", server_config.bind, server_config.port);
     log!("  Server on {}", addr);
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .route("/api/{path:.*}", web::to(api_handler))
             .route("/direct/{path:.*}", web::to(direct_handler))
     })
-    .bind_rustls_0_23(addr, config)?
-    .run()
-    .await?;
+    .disable_signals()
+    .shutdown_timeout(shutdown::GRACE.as_secs())
+    .bind_rustls_0_23(addr, config);
+    let server = match server {
+        Ok(server) => server,
+        Err(e) => {
+            health::set_listener(Listener::Https, ListenerState::Failed);
+            return Err(e.into());
+        }
+    };
+    health::set_listener(Listener::Https, ListenerState::Bound);
+
+    let server = server.run();
+    stop_on_shutdown(&server);
+    let result = server.await;
+    if !shutdown::is_shutting_down() {
+        health::set_listener(Listener::Https, ListenerState::Failed);
+    }
+    result?;
 
     Ok(())
 }
+
+/// Binds the admin listener, which also serves the health probes. The
+/// returned server runs once it is awaited or spawned.
+pub fn start_admin(admin_config: &AdminConfig) -> anyhow::Result<Server> {
+    let addr = format!("{}:{}", admin_config.bind, admin_config.port);
+    log!("  Admin API on {}", addr);
+
+    let server = HttpServer::new(|| {
+        App::new()
+            .route("/admin/{path:.*}", web::to(admin_handler))
+            .route("/metrics", web::get().to(metrics_handler))
+            .route("/healthz", web::get().to(healthz_handler))
+            .route("/readyz", web::get().to(readyz_handler))
+    })
+    .disable_signals()
+    .shutdown_timeout(shutdown::GRACE.as_secs())
+    .bind(addr)?
+    .run();
+    stop_on_shutdown(&server);
+
+    Ok(server)
+}
+
+/// Stops accepting connections on shutdown and lets running requests finish.
+fn stop_on_shutdown(server: &Server) {
+    let handle = server.handle();
+    tokio::spawn(async move {
+        shutdown::requested().await;
+        handle.stop(true).await;
+    });
+}
+
 async fn direct_handler(req: HttpRequest) -> impl Responder {
+    let started = Instant::now();
     let path = req.uri().path().to_string();
     let short = path.replace("/direct/", "");
 
-    if let Ok(long) = get_short_link(&short).await {
+    let res = if let Ok(long) = get_short_link(&short).await {
+        metrics::record_redirect(true);
         HttpResponse::TemporaryRedirect()
             .append_header((header::LOCATION, long))
             .finish()
     } else {
+        metrics::record_redirect(false);
         HttpResponse::TemporaryRedirect()
-            .append_header((header::LOCATION, "https://tensamin.net"))
+            .append_header((
+                header::LOCATION,
+                config::get().short_link.fallback_url.as_str(),
+            ))
             .finish()
-    }
+    };
+    record_request("/direct".to_string(), &res, started);
+    res
 }
 
 async fn api_handler(req: HttpRequest, body: web::Bytes) -> HttpResponse {
     let path = req.uri().path().to_string();
     let body_string = String::from_utf8_lossy(&body).to_string();
+    let client_ip = req
+        .connection_info()
+        .realip_remote_addr()
+        .map(str::to_string);
 
-    api::handle(&path, Some(body_string)).await
+    let started = Instant::now();
+    let res = api::handle(&path, req.query_string(), client_ip, Some(body_string)).await;
+    record_request(route_label(&path), &res, started);
+    res
+}
+
+async fn admin_handler(req: HttpRequest, body: web::Bytes) -> HttpResponse {
+    let authorization = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok());
+    if !admin::authorized(authorization) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let path = req.uri().path().to_string();
+    let body_string = String::from_utf8_lossy(&body).to_string();
+
+    let started = Instant::now();
+    let res = admin::handle(req.method(), &path, Some(body_string)).await;
+    record_request(route_label(&path), &res, started);
+    res
+}
+
+async fn metrics_handler(req: HttpRequest) -> HttpResponse {
+    let authorization = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok());
+    if !admin::authorized(authorization) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "text/plain; version=0.0.4"))
+        .body(metrics::render().await)
+}
+
+async fn healthz_handler() -> HttpResponse {
+    let (live, body) = health::live();
+    probe_response(live, body)
+}
+
+async fn readyz_handler() -> HttpResponse {
+    let (ready, body) = health::ready().await;
+    probe_response(ready, body)
+}
+
+fn probe_response(ok: bool, body: json::JsonValue) -> HttpResponse {
+    let mut res = if ok {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    res.insert_header((header::CONTENT_TYPE, "application/json"))
+        .body(body.dump())
+}
+
+fn record_request(route: String, res: &HttpResponse, started: Instant) {
+    metrics::record_http(route, res.status().as_u16(), started.elapsed());
+}
+
+/// The route `api::handle` or `admin::handle` serves for the path, with ids
+/// and usernames replaced. Anything else is `other`, so the set of metric
+/// labels is fixed and cannot grow with requests for unknown paths.
+fn route_label(path: &str) -> String {
+    let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let label = match parts.as_slice() {
+        ["api", "download", "iota_frontend"] => "/api/download/iota_frontend",
+        ["api", "get", "omikron"] => "/api/get/omikron",
+        ["api", "get", "omikron", _] => "/api/get/omikron/{id}",
+        ["api", "get", "id", _] => "/api/get/id/{id}",
+        ["api", "get", "public_key"] => "/api/get/public_key",
+        ["api", "get", "user", _] => "/api/get/user/{id}",
+        ["admin", "topology"] => "/admin/topology",
+        ["admin", "omikrons"] => "/admin/omikrons",
+        ["admin", "omikrons", _] => "/admin/omikrons/{id}",
+        ["admin", "omikrons", _, "stats"] => "/admin/omikrons/{id}/stats",
+        ["admin", "omikrons", _, "ping"] => "/admin/omikrons/{id}/ping",
+        ["admin", "omikrons", _, "rotate_key"] => "/admin/omikrons/{id}/rotate_key",
+        ["admin", "omikrons", _, "update"] => "/admin/omikrons/{id}/update",
+        ["admin", "omikrons", _, "draining"] => "/admin/omikrons/{id}/draining",
+        ["admin", "omikrons", _, "drain"] => "/admin/omikrons/{id}/drain",
+        ["admin", "omikrons", _, "decommission"] => "/admin/omikrons/{id}/decommission",
+        _ => "other",
+    };
+    label.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_routes_replace_ids() {
+        assert_eq!(route_label("/api/get/omikron"), "/api/get/omikron");
+        assert_eq!(route_label("/api/get/omikron/42"), "/api/get/omikron/{id}");
+        assert_eq!(route_label("/api/get/id/alice"), "/api/get/id/{id}");
+        assert_eq!(route_label("/api/get/user/7/"), "/api/get/user/{id}");
+        assert_eq!(
+            route_label("/admin/omikrons/3/decommission"),
+            "/admin/omikrons/{id}/decommission"
+        );
+    }
+
+    #[test]
+    fn unknown_paths_share_one_label() {
+        for path in [
+            "/api/foo/bar",
+            "/api/get/omikron/1/extra",
+            "/api",
+            "/admin/omikrons/3/unknown",
+            "/",
+        ] {
+            assert_eq!(route_label(path), "other", "{path}");
+        }
+    }
 }
@@ -1,3 +1,4 @@
+use crate::config;
 use dashmap::DashMap;
 use once_cell::sync::Lazy;
 use rand::{Rng, thread_rng};
@@ -11,7 +12,8 @@ pub async fn add_short_link(long: &str) -> Result<String, ()> {
     LINKS.insert(raw.clone(), long.to_string());
 
     Ok(format!(
-        "https://omega.tensamin.net/direct/{}",
+        "{}/{}",
+        config::get().short_link.base_url.trim_end_matches('/'),
         format_with_dashes(&raw)
     ))
 }
@@ -53,6 +55,10 @@ pub async fn get_short_link(short: &str) -> Result<String, ()> {
     }
 }
 
+pub fn count() -> usize {
+    LINKS.len()
+}
+
 /* ---------------- helpers ---------------- */
 
 fn short_length() -> usize {
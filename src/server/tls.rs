@@ -0,0 +1,367 @@
+use crate::log;
+use rustls::{
+    Error, InconsistentKeys,
+    crypto::{CryptoProvider, aws_lc_rs::default_provider},
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use rustls_pemfile::Item;
+use std::{collections::HashMap, fs::File, io::BufReader, path::Path, sync::Arc};
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsConfigError {
+    #[error("Could not open {path}: {source}")]
+    Open {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("Could not parse PEM data in {path}: {source}")]
+    Pem {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("No certificates found in {0}")]
+    NoCertificates(String),
+    #[error("No private key found in {0} (expected PKCS#8, PKCS#1 RSA or SEC1 EC PEM)")]
+    NoPrivateKey(String),
+    #[error(
+        "None of the {count} private key(s) in {key_path} match the certificate in {cert_path}"
+    )]
+    NoMatchingKey {
+        cert_path: String,
+        key_path: String,
+        count: usize,
+    },
+    #[error("Invalid SNI host name: {0}")]
+    InvalidSniName(String),
+    #[error("Could not read SNI directory {path}: {source}")]
+    SniDirectory {
+        path: String,
+        source: std::io::Error,
+    },
+}
+
+/// Loads a certificate chain and the private key that belongs to it.
+///
+/// The key file may contain any PEM key type supported by rustls-pemfile
+/// (PKCS#8, PKCS#1 RSA, SEC1 EC), and more than one key; the first key whose
+/// public half matches the leaf certificate is used. A key whose public half
+/// cannot be determined is only used if no key matches outright.
+pub fn load_certified_key(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<CertifiedKey, TlsConfigError> {
+    let cert_chain = read_certificates(cert_path)?;
+    let keys = read_private_keys(key_path)?;
+
+    let provider = CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(default_provider()));
+
+    let count = keys.len();
+    let mut unverified = None;
+    for key in keys {
+        let Ok(signing_key) = provider.key_provider.load_private_key(key) else {
+            continue;
+        };
+        let certified = CertifiedKey::new(cert_chain.clone(), signing_key);
+        match certified.keys_match() {
+            Ok(()) => return Ok(certified),
+            Err(Error::InconsistentKeys(InconsistentKeys::Unknown)) if unverified.is_none() => {
+                unverified = Some(certified);
+            }
+            Err(_) => {}
+        }
+    }
+
+    unverified.ok_or(TlsConfigError::NoMatchingKey {
+        cert_path: cert_path.display().to_string(),
+        key_path: key_path.display().to_string(),
+        count,
+    })
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, TlsConfigError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|source| TlsConfigError::Open {
+            path: path.display().to_string(),
+            source,
+        })
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsConfigError> {
+    let mut reader = open(path)?;
+    let certs: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<_, _>>()
+        .map_err(|source| TlsConfigError::Pem {
+            path: path.display().to_string(),
+            source,
+        })?;
+
+    if certs.is_empty() {
+        return Err(TlsConfigError::NoCertificates(path.display().to_string()));
+    }
+    Ok(certs)
+}
+
+fn read_private_keys(path: &Path) -> Result<Vec<PrivateKeyDer<'static>>, TlsConfigError> {
+    let mut reader = open(path)?;
+    let mut keys = Vec::new();
+
+    for item in rustls_pemfile::read_all(&mut reader) {
+        let item = item.map_err(|source| TlsConfigError::Pem {
+            path: path.display().to_string(),
+            source,
+        })?;
+        match item {
+            Item::Pkcs8Key(key) => keys.push(key.into()),
+            Item::Pkcs1Key(key) => keys.push(key.into()),
+            Item::Sec1Key(key) => keys.push(key.into()),
+            _ => {}
+        }
+    }
+
+    if keys.is_empty() {
+        return Err(TlsConfigError::NoPrivateKey(path.display().to_string()));
+    }
+    Ok(keys)
+}
+
+// ==================================================
+// SNI
+// ==================================================
+
+/// Picks a certificate by the SNI host name sent by the client and falls back
+/// to the default certificate for unknown names or clients without SNI.
+#[derive(Debug)]
+pub struct SniResolver {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    pub fn new(default: CertifiedKey) -> Self {
+        Self {
+            default: Arc::new(default),
+            by_name: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, host_name: &str, key: CertifiedKey) -> Result<(), TlsConfigError> {
+        let name = host_name.trim().to_ascii_lowercase();
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '*')
+        {
+            return Err(TlsConfigError::InvalidSniName(host_name.to_string()));
+        }
+        self.by_name.insert(name, Arc::new(key));
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Option<Arc<CertifiedKey>> {
+        let name = name.to_ascii_lowercase();
+        if let Some(key) = self.by_name.get(&name) {
+            return Some(key.clone());
+        }
+        // *.example.com covers exactly one extra label
+        let (_, parent) = name.split_once('.')?;
+        self.by_name.get(&format!("*.{}", parent)).cloned()
+    }
+}
+
+impl SniResolver {
+    fn resolve_name(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        server_name
+            .and_then(|name| self.lookup(name))
+            .unwrap_or_else(|| self.default.clone())
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.resolve_name(client_hello.server_name()))
+    }
+}
+
+/// Builds the certificate resolver for the HTTPS server.
+///
+/// `sni_dir` may contain one sub directory per host name, each holding a
+/// `cert.pem` and `key.pem`. Without it, only the default certificate is served.
+pub fn build_resolver(
+    cert_path: &Path,
+    key_path: &Path,
+    sni_dir: Option<&Path>,
+) -> Result<SniResolver, TlsConfigError> {
+    let mut resolver = SniResolver::new(load_certified_key(cert_path, key_path)?);
+
+    let Some(sni_dir) = sni_dir.filter(|d| d.is_dir()) else {
+        return Ok(resolver);
+    };
+
+    let entries = std::fs::read_dir(sni_dir).map_err(|source| TlsConfigError::SniDirectory {
+        path: sni_dir.display().to_string(),
+        source,
+    })?;
+
+    for entry in entries.flatten() {
+        let dir = entry.path();
+        if !dir.is_dir() {
+            continue;
+        }
+        let host_name = entry.file_name().to_string_lossy().to_string();
+        let key = load_certified_key(&dir.join("cert.pem"), &dir.join("key.pem"))?;
+        resolver.add(&host_name, key)?;
+        log!("  SNI certificate for {}", host_name);
+    }
+
+    Ok(resolver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{Engine as _, engine::general_purpose::STANDARD};
+    use rcgen::{CertificateParams, KeyPair, PKCS_ECDSA_P256_SHA256, PKCS_RSA_SHA256};
+    use std::path::PathBuf;
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("omega-tls-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn certificate(host_name: &str, key: &KeyPair) -> String {
+        CertificateParams::new(vec![host_name.to_string()])
+            .unwrap()
+            .self_signed(key)
+            .unwrap()
+            .pem()
+    }
+
+    fn pem(label: &str, der: &[u8]) -> String {
+        let encoded = STANDARD.encode(der);
+        let mut pem = format!("-----BEGIN {}-----\n", label);
+        for line in encoded.as_bytes().chunks(64) {
+            pem.push_str(std::str::from_utf8(line).unwrap());
+            pem.push('\n');
+        }
+        pem + &format!("-----END {}-----\n", label)
+    }
+
+    /// Reads one DER header, returns the contents and what follows them.
+    fn der_item(der: &[u8]) -> (&[u8], &[u8]) {
+        let (len, start) = match der[1] {
+            len @ 0..0x80 => (len as usize, 2),
+            long => {
+                let count = (long & 0x7f) as usize;
+                let len = der[2..2 + count]
+                    .iter()
+                    .fold(0usize, |len, byte| len << 8 | *byte as usize);
+                (len, 2 + count)
+            }
+        };
+        der[start..].split_at(len)
+    }
+
+    /// The PKCS#1 or SEC1 key wrapped in a PKCS#8 key.
+    fn unwrap_pkcs8(der: &[u8]) -> Vec<u8> {
+        let (info, _) = der_item(der);
+        let (_version, rest) = der_item(info);
+        let (_algorithm, rest) = der_item(rest);
+        let (key, _) = der_item(rest);
+        key.to_vec()
+    }
+
+    fn load(name: &str, cert: &str, key: &str) -> Result<CertifiedKey, TlsConfigError> {
+        let cert_path = temp_file(&format!("{name}-cert.pem"), cert);
+        let key_path = temp_file(&format!("{name}-key.pem"), key);
+        let result = load_certified_key(&cert_path, &key_path);
+        let _ = std::fs::remove_file(cert_path);
+        let _ = std::fs::remove_file(key_path);
+        result
+    }
+
+    #[test]
+    fn loads_every_key_format() {
+        let ec = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let rsa = KeyPair::generate_for(&PKCS_RSA_SHA256).unwrap();
+        let ec_cert = certificate("example.com", &ec);
+        let rsa_cert = certificate("example.com", &rsa);
+
+        assert!(load("pkcs8-ec", &ec_cert, &ec.serialize_pem()).is_ok());
+        assert!(load("pkcs8-rsa", &rsa_cert, &rsa.serialize_pem()).is_ok());
+        let pkcs1 = pem("RSA PRIVATE KEY", &unwrap_pkcs8(&rsa.serialize_der()));
+        assert!(load("pkcs1", &rsa_cert, &pkcs1).is_ok());
+        let sec1 = pem("EC PRIVATE KEY", &unwrap_pkcs8(&ec.serialize_der()));
+        assert!(load("sec1", &ec_cert, &sec1).is_ok());
+    }
+
+    #[test]
+    fn picks_the_matching_key() {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let other = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let cert = certificate("example.com", &key);
+
+        let both = other.serialize_pem() + &key.serialize_pem();
+        assert!(load("both", &cert, &both).is_ok());
+
+        let error = load("mismatch", &cert, &other.serialize_pem()).unwrap_err();
+        assert!(matches!(
+            error,
+            TlsConfigError::NoMatchingKey { count: 1, .. }
+        ));
+        assert!(error.to_string().contains("mismatch-key.pem"), "{error}");
+    }
+
+    #[test]
+    fn empty_files_are_described() {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let cert = certificate("example.com", &key);
+
+        let error = load("no-key", &cert, "").unwrap_err();
+        assert!(matches!(error, TlsConfigError::NoPrivateKey(_)));
+        assert!(error.to_string().contains("no-key-key.pem"), "{error}");
+
+        let error = load("no-cert", "", &key.serialize_pem()).unwrap_err();
+        assert!(matches!(error, TlsConfigError::NoCertificates(_)));
+        assert!(error.to_string().contains("no-cert-cert.pem"), "{error}");
+    }
+
+    #[test]
+    fn resolves_by_server_name() {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let certified = |host_name: &str| {
+            load(
+                &format!("sni-{host_name}"),
+                &certificate(host_name, &key),
+                &key.serialize_pem(),
+            )
+            .unwrap()
+        };
+        let mut resolver = SniResolver::new(certified("default.test"));
+        resolver
+            .add("Example.com", certified("example.com"))
+            .unwrap();
+        resolver
+            .add("*.example.org", certified("wildcard.example.org"))
+            .unwrap();
+        assert!(resolver.add("bad name", certified("bad.test")).is_err());
+
+        let served = |name: Option<&str>| resolver.resolve_name(name).cert[0].clone();
+        let default = resolver.default.cert[0].clone();
+        let exact = resolver.by_name["example.com"].cert[0].clone();
+        let wildcard = resolver.by_name["*.example.org"].cert[0].clone();
+
+        assert_eq!(served(Some("EXAMPLE.com")), exact);
+        assert_eq!(served(Some("www.example.org")), wildcard);
+        // A wildcard covers exactly one label
+        assert_eq!(served(Some("a.b.example.org")), default);
+        assert_eq!(served(Some("example.org")), default);
+        assert_eq!(served(Some("unknown.test")), default);
+        assert_eq!(served(None), default);
+    }
+}
@@ -19,6 +19,18 @@ impl UserStatus {
     pub fn to_string(&self) -> String {
         format!("{:?}", self)
     }
+    /// The states a user can be in, as opposed to the IOTA states.
+    pub fn is_user_status(&self) -> bool {
+        match self {
+            UserStatus::user_offline
+            | UserStatus::user_online
+            | UserStatus::user_dnd
+            | UserStatus::user_idle
+            | UserStatus::user_wc
+            | UserStatus::user_borked => true,
+            UserStatus::iota_offline | UserStatus::iota_online | UserStatus::iota_borked => false,
+        }
+    }
     pub fn from_str(s: &str) -> Option<UserStatus> {
         for sel in UserStatus::iter() {
             if &sel.to_string() == s {
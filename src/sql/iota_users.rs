@@ -0,0 +1,38 @@
+use crate::sql::sql;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Which users are registered on an IOTA only changes through the user
+// functions in `sql`, which invalidate the affected entries here.
+
+// IotaID -> UserIDs
+static CACHE: Lazy<DashMap<i64, Vec<i64>>> = Lazy::new(DashMap::new);
+
+// Bumped on every invalidation, so a query that raced with one is not cached
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// User ids registered on an IOTA, from the cache if possible.
+pub async fn user_ids(iota_id: i64) -> Result<Vec<i64>, sqlx::Error> {
+    if let Some(user_ids) = CACHE.get(&iota_id) {
+        return Ok(user_ids.clone());
+    }
+
+    let generation = GENERATION.load(Ordering::Acquire);
+    let user_ids = sql::get_user_ids_by_iota_id(iota_id).await?;
+    if GENERATION.load(Ordering::Acquire) == generation {
+        CACHE.insert(iota_id, user_ids.clone());
+    }
+    Ok(user_ids)
+}
+
+pub fn invalidate(iota_id: i64) {
+    GENERATION.fetch_add(1, Ordering::AcqRel);
+    CACHE.remove(&iota_id);
+}
+
+/// Drops every entry the user appears in, for when its old IOTA is unknown.
+pub fn invalidate_user(user_id: i64) {
+    GENERATION.fetch_add(1, Ordering::AcqRel);
+    CACHE.retain(|_, user_ids| !user_ids.contains(&user_id));
+}
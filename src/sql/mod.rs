@@ -1,3 +1,5 @@
 pub mod connection_status;
+pub mod iota_users;
+pub mod presence_store;
 pub mod sql;
 pub mod user_online_tracker;
@@ -0,0 +1,185 @@
+use crate::log_err;
+use crate::sql::{sql, user_online_tracker};
+use crate::util::logger::PrintType;
+use dashmap::DashSet;
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{mpsc, oneshot};
+
+// Presence timestamps are written here instead of in the tracker, so tracking
+// never waits for the database.
+
+static WRITER: OnceLock<mpsc::UnboundedSender<WriterMessage>> = OnceLock::new();
+
+// UserIDs hiding when they were last seen, loaded once and kept in sync by
+// `set_hidden`, so reading a last seen time needs no hidden flag query
+static HIDDEN: Lazy<DashSet<i64>> = Lazy::new(DashSet::new);
+
+/// Events arriving within this window are merged into one write per id.
+const BATCH_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Subject {
+    User(i64),
+    Iota(i64),
+}
+
+struct PresenceEvent {
+    subject: Subject,
+    online: bool,
+    at: u64,
+}
+
+enum WriterMessage {
+    Event(PresenceEvent),
+    /// Write everything received so far without waiting for the window
+    Flush(oneshot::Sender<()>),
+}
+
+#[derive(Default)]
+struct PendingWrite {
+    last_online: u64,
+    last_offline: Option<u64>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn record(subject: Subject, online: bool) {
+    if let Some(writer) = WRITER.get() {
+        let _ = writer.send(WriterMessage::Event(PresenceEvent {
+            subject,
+            online,
+            at: now_secs(),
+        }));
+    }
+}
+
+pub fn record_user(user_id: i64, online: bool) {
+    record(Subject::User(user_id), online);
+}
+
+pub fn record_iota(iota_id: i64, online: bool) {
+    record(Subject::Iota(iota_id), online);
+}
+
+/// Loads the hidden flags and starts the background writer. Needs an
+/// initialized database pool.
+pub async fn start() -> Result<(), sqlx::Error> {
+    for user_id in sql::get_users_hiding_last_seen().await? {
+        HIDDEN.insert(user_id);
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<WriterMessage>();
+    if WRITER.set(tx).is_err() {
+        return Ok(());
+    }
+
+    tokio::spawn(async move {
+        while let Some(first) = rx.recv().await {
+            let mut batch: HashMap<Subject, PendingWrite> = HashMap::new();
+            let mut flushed = Vec::new();
+            receive(&mut batch, &mut flushed, first);
+
+            let window = tokio::time::Instant::now() + BATCH_WINDOW;
+            while flushed.is_empty() {
+                match tokio::time::timeout_at(window, rx.recv()).await {
+                    Ok(Some(message)) => receive(&mut batch, &mut flushed, message),
+                    Ok(None) | Err(_) => break,
+                }
+            }
+            while let Ok(message) = rx.try_recv() {
+                receive(&mut batch, &mut flushed, message);
+            }
+
+            for (subject, pending) in batch {
+                let result = match subject {
+                    Subject::User(id) => {
+                        let result =
+                            sql::write_user_presence(id, pending.last_online, pending.last_offline)
+                                .await;
+                        // Persisted now, the tracker no longer has to remember it
+                        if result.is_ok() {
+                            user_online_tracker::forget_last_seen(id, pending.last_online);
+                        }
+                        result
+                    }
+                    Subject::Iota(id) => {
+                        sql::write_iota_presence(id, pending.last_online, pending.last_offline)
+                            .await
+                    }
+                };
+                if let Err(e) = result {
+                    log_err!(0, PrintType::General, "Could not write presence: {}", e);
+                }
+            }
+            for done in flushed {
+                let _ = done.send(());
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Writes everything recorded so far and waits for it. Returns right away
+/// if the writer was never started.
+pub async fn flush() {
+    let Some(writer) = WRITER.get() else {
+        return;
+    };
+    let (tx, rx) = oneshot::channel();
+    if writer.send(WriterMessage::Flush(tx)).is_ok() {
+        let _ = rx.await;
+    }
+}
+
+/// When the user was last seen online, `None` if unknown or hidden by the user.
+pub async fn last_seen(user_id: i64) -> Option<u64> {
+    if HIDDEN.contains(&user_id) {
+        return None;
+    }
+    // The tracker only remembers online users and times not written yet,
+    // both newer than anything in the database
+    if let Some(last_seen) = user_online_tracker::get_last_seen(user_id) {
+        return Some(last_seen);
+    }
+    sql::get_user_presence(user_id).await.ok()?.0
+}
+
+pub async fn set_hidden(user_id: i64, hide: bool) -> Result<(), sqlx::Error> {
+    sql::set_hide_last_seen(user_id, hide).await?;
+    if hide {
+        HIDDEN.insert(user_id);
+    } else {
+        HIDDEN.remove(&user_id);
+    }
+    Ok(())
+}
+
+fn receive(
+    batch: &mut HashMap<Subject, PendingWrite>,
+    flushed: &mut Vec<oneshot::Sender<()>>,
+    message: WriterMessage,
+) {
+    match message {
+        WriterMessage::Event(event) => merge(batch, event),
+        WriterMessage::Flush(done) => flushed.push(done),
+    }
+}
+
+fn merge(batch: &mut HashMap<Subject, PendingWrite>, event: PresenceEvent) {
+    let pending = batch.entry(event.subject).or_default();
+    // Going offline also means the subject was online until then
+    pending.last_online = pending.last_online.max(event.at);
+    if !event.online {
+        pending.last_offline = Some(event.at);
+    }
+}
@@ -1,9 +1,13 @@
-use crate::log;
+use crate::config::DatabaseConfig;
+use crate::sql::iota_users;
+use crate::util::metrics;
 use once_cell::sync::Lazy;
-use sqlx::{MySql, Pool, Row, mysql::MySqlPoolOptions};
+use sqlx::{
+    MySql, Pool, Row,
+    mysql::{MySqlConnectOptions, MySqlPoolOptions},
+};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::{
-    env,
     sync::Arc,
     time::{SystemTime, UNIX_EPOCH},
 };
@@ -22,17 +26,17 @@ use crate::sql::{
 
 static SQL_DB: Lazy<Arc<RwLock<Option<Pool<MySql>>>>> = Lazy::new(|| Arc::new(RwLock::new(None)));
 
-pub async fn connect() -> Result<Pool<MySql>, sqlx::Error> {
-    let user = env::var("DB_USERNAME").expect("DB_USERNAME is not set");
-    let passwd = env::var("DB_PASSWD").expect("DB_PASSWD is not set");
-    let table = env::var("DB_TABLE").expect("DB_TABLE is not set");
+pub async fn connect(config: &DatabaseConfig) -> Result<Pool<MySql>, sqlx::Error> {
+    let options = MySqlConnectOptions::new()
+        .host(&config.host)
+        .port(config.port)
+        .username(&config.username)
+        .password(&config.password)
+        .database(&config.name);
 
     MySqlPoolOptions::new()
-        .max_connections(200)
-        .connect(&format!(
-            "mysql://{}:{}@127.0.0.1:3306/{}",
-            user, passwd, table
-        ))
+        .max_connections(config.max_connections)
+        .connect_with(options)
         .await
 }
 // Omega
@@ -50,9 +54,45 @@ pub async fn connect() -> Result<Pool<MySql>, sqlx::Error> {
 //   - Iota
 //     - User
 //     - User
-pub async fn initialize_db() -> Result<(), sqlx::Error> {
-    let pool = connect().await?;
-    let mut db_lock = SQL_DB.write().await;
+pub async fn initialize_db(config: &DatabaseConfig) -> Result<(), sqlx::Error> {
+    let pool = connect(config).await?;
+    *SQL_DB.write().await = Some(pool);
+    Ok(())
+}
+
+/// Open and idle connections of the pool, `None` before it is initialized.
+pub async fn pool_usage() -> Option<(u32, usize)> {
+    let db_lock = SQL_DB.read().await;
+    let pool = db_lock.as_ref()?;
+    Some((pool.size(), pool.num_idle()))
+}
+
+/// Waits for running queries and closes every connection of the pool. The
+/// pool stays in place, so later queries fail with `PoolClosed` instead of
+/// finding no pool.
+pub async fn close() {
+    let pool = SQL_DB.read().await.as_ref().cloned();
+    if let Some(pool) = pool {
+        pool.close().await;
+    }
+}
+
+/// Whether the database answers, `false` before the pool is initialized.
+pub async fn ping() -> bool {
+    let Some(pool) = SQL_DB.read().await.as_ref().cloned() else {
+        return false;
+    };
+    sqlx::query("SELECT 1").execute(&pool).await.is_ok()
+}
+
+pub async fn migrate() -> Result<(), sqlx::Error> {
+    let pool = {
+        let db_lock = SQL_DB.read().await;
+        db_lock
+            .as_ref()
+            .cloned()
+            .expect("Database pool not initialized")
+    };
     // create tables
     // with indexes
     let _ = sqlx::query(
@@ -69,11 +109,16 @@ pub async fn initialize_db() -> Result<(), sqlx::Error> {
         public_key TEXT NOT NULL COLLATE utf8mb4_bin,
         private_key_hash TEXT NOT NULL COLLATE utf8mb4_bin DEFAULT '',
         iota_id BIGINT UNSIGNED NOT NULL,
-        token VARCHAR(255) NOT NULL UNIQUE COLLATE utf8mb4_bin
+        token VARCHAR(255) NOT NULL UNIQUE COLLATE utf8mb4_bin,
+        INDEX idx_users_iota_id (iota_id)
         )",
     )
     .execute(&pool)
     .await;
+    // fails once the index exists
+    let _ = sqlx::query("CREATE INDEX idx_users_iota_id ON users (iota_id)")
+        .execute(&pool)
+        .await;
     let _ = sqlx::query(
         "CREATE TABLE IF NOT EXISTS
         iotas (
@@ -89,11 +134,21 @@ pub async fn initialize_db() -> Result<(), sqlx::Error> {
         id BIGINT UNSIGNED NOT NULL PRIMARY KEY,
         public_key VARCHAR(255) NOT NULL COLLATE utf8mb4_bin,
         location VARCHAR(255) NOT NULL COLLATE utf8mb4_bin,
-        ip_address VARCHAR(255) NOT NULL COLLATE utf8mb4_bin
+        ip_address VARCHAR(255) NOT NULL COLLATE utf8mb4_bin,
+        previous_public_key VARCHAR(255) COLLATE utf8mb4_bin,
+        previous_key_expires BIGINT UNSIGNED
         )",
     )
     .execute(&pool)
     .await;
+    // fails once the columns exist
+    let _ = sqlx::query(
+        "ALTER TABLE omikrons
+        ADD COLUMN previous_public_key VARCHAR(255) COLLATE utf8mb4_bin,
+        ADD COLUMN previous_key_expires BIGINT UNSIGNED",
+    )
+    .execute(&pool)
+    .await;
     let _ = sqlx::query(
         "CREATE TABLE IF NOT EXISTS
         notifications (
@@ -105,7 +160,27 @@ pub async fn initialize_db() -> Result<(), sqlx::Error> {
     )
     .execute(&pool)
     .await;
-    *db_lock = Some(pool);
+    let _ = sqlx::query(
+        "CREATE TABLE IF NOT EXISTS
+        user_presence (
+        user_id BIGINT UNSIGNED NOT NULL PRIMARY KEY,
+        last_online BIGINT UNSIGNED,
+        last_offline BIGINT UNSIGNED,
+        hide_last_seen BOOLEAN NOT NULL DEFAULT FALSE
+        )",
+    )
+    .execute(&pool)
+    .await;
+    let _ = sqlx::query(
+        "CREATE TABLE IF NOT EXISTS
+        iota_presence (
+        iota_id BIGINT UNSIGNED NOT NULL PRIMARY KEY,
+        last_online BIGINT UNSIGNED,
+        last_offline BIGINT UNSIGNED
+        )",
+    )
+    .execute(&pool)
+    .await;
     Ok(())
 }
 
@@ -178,6 +253,7 @@ pub async fn get_by_username(
             .cloned()
             .expect("Database pool not initialized")
     };
+    let _timer = metrics::time_sql("get_by_username");
 
     let row = sqlx::query(
         "SELECT id, iota_id, username, display, status, about, avatar, sub_level, sub_end, public_key, private_key_hash, token FROM users WHERE username = ?",
@@ -246,6 +322,7 @@ pub async fn get_by_user_id(
             .cloned()
             .expect("Database pool not initialized")
     };
+    let _timer = metrics::time_sql("get_by_user_id");
 
     let row = sqlx::query(
         "SELECT id, iota_id, username, display, status, about, avatar, sub_level, sub_end, public_key, private_key_hash, token FROM users WHERE id = CAST(? AS UNSIGNED)",
@@ -288,25 +365,9 @@ pub async fn get_by_user_id(
     }
 }
 
-pub async fn get_users_by_iota_id(
-    iota_id_param: i64,
-) -> Result<
-    Vec<(
-        i64,
-        i64,
-        String,
-        Option<String>,
-        Option<String>,
-        Option<String>,
-        Option<Vec<u8>>,
-        i32,
-        i64,
-        String,
-        String,
-        String,
-    )>,
-    sqlx::Error,
-> {
+/// Only the ids, for the tracker. Go through `iota_users::user_ids`, which
+/// caches the result.
+pub async fn get_user_ids_by_iota_id(iota_id: i64) -> Result<Vec<i64>, sqlx::Error> {
     let pool = {
         let db_lock = SQL_DB.read().await;
         db_lock
@@ -314,46 +375,14 @@ pub async fn get_users_by_iota_id(
             .cloned()
             .expect("Database pool not initialized")
     };
+    let _timer = metrics::time_sql("get_user_ids_by_iota_id");
 
-    let rows = sqlx::query(
-        "SELECT id, iota_id, username, display, status, about, avatar, sub_level, sub_end, public_key, private_key_hash, token FROM users WHERE iota_id = CAST(? AS UNSIGNED)",
-    )
-    .bind(iota_id_param)
-    .fetch_all(&pool)
-    .await?;
-
-    let mut users = Vec::new();
-    for row in rows {
-        let id: i64 = row.get("id");
-        let iota_id: i64 = row.get("iota_id");
-        let username: String = row.get("username");
-        let display: Option<Vec<u8>> = row.get("display");
-        let status: Option<Vec<u8>> = row.get("status");
-        let about: Option<Vec<u8>> = row.get("about");
-        let avatar: Option<Vec<u8>> = row.get("avatar");
-        let sub_level: i32 = row.get("sub_level");
-        let sub_end: i64 = row.get("sub_end");
-        let public_key: String = row.get("public_key");
-        let private_key_hash: String = row.get("private_key_hash");
-        let token: Vec<u8> = row.get("token");
-
-        users.push((
-            id,
-            iota_id,
-            username,
-            display.map(|d| String::from_utf8_lossy(&d).to_string()),
-            status.map(|s| String::from_utf8_lossy(&s).to_string()),
-            about.map(|a| String::from_utf8_lossy(&a).to_string()),
-            avatar,
-            sub_level,
-            sub_end,
-            public_key,
-            private_key_hash,
-            String::from_utf8_lossy(&token).to_string(),
-        ));
-    }
+    let rows = sqlx::query("SELECT id FROM users WHERE iota_id = CAST(? AS UNSIGNED)")
+        .bind(iota_id)
+        .fetch_all(&pool)
+        .await?;
 
-    Ok(users)
+    Ok(rows.iter().map(|row| row.get("id")).collect())
 }
 
 pub async fn change_username(id: i64, new_username: String) -> Result<(), sqlx::Error> {
@@ -364,6 +393,7 @@ pub async fn change_username(id: i64, new_username: String) -> Result<(), sqlx::
             .cloned()
             .expect("Database pool not initialized")
     };
+    let _timer = metrics::time_sql("change_username");
 
     sqlx::query("UPDATE users SET username = ? WHERE id = CAST(? AS UNSIGNED)")
         .bind(new_username)
@@ -382,6 +412,7 @@ pub async fn change_display_name(id: i64, new_display: String) -> Result<(), sql
             .cloned()
             .expect("Database pool not initialized")
     };
+    let _timer = metrics::time_sql("change_display_name");
 
     sqlx::query("UPDATE users SET display = ? WHERE id = CAST(? AS UNSIGNED)")
         .bind(new_display)
@@ -400,6 +431,7 @@ pub async fn change_avatar(id: i64, new_avatar: String) -> Result<(), sqlx::Erro
             .cloned()
             .expect("Database pool not initialized")
     };
+    let _timer = metrics::time_sql("change_avatar");
 
     sqlx::query("UPDATE users SET avatar = ? WHERE id = CAST(? AS UNSIGNED)")
         .bind(new_avatar)
@@ -418,6 +450,7 @@ pub async fn change_about(id: i64, new_about: String) -> Result<(), sqlx::Error>
             .cloned()
             .expect("Database pool not initialized")
     };
+    let _timer = metrics::time_sql("change_about");
 
     sqlx::query("UPDATE users SET about = ? WHERE id = CAST(? AS UNSIGNED)")
         .bind(new_about)
@@ -436,6 +469,7 @@ pub async fn change_status(id: i64, new_status: String) -> Result<(), sqlx::Erro
             .cloned()
             .expect("Database pool not initialized")
     };
+    let _timer = metrics::time_sql("change_status");
 
     sqlx::query("UPDATE users SET status = ? WHERE id = CAST(? AS UNSIGNED)")
         .bind(new_status)
@@ -454,11 +488,17 @@ pub async fn delete_user(id: i64) -> Result<(), sqlx::Error> {
             .cloned()
             .expect("Database pool not initialized")
     };
+    let _timer = metrics::time_sql("delete_user");
 
     sqlx::query("DELETE FROM users WHERE id = CAST(? AS UNSIGNED)")
         .bind(id)
         .execute(&pool)
         .await?;
+    sqlx::query("DELETE FROM user_presence WHERE user_id = CAST(? AS UNSIGNED)")
+        .bind(id)
+        .execute(&pool)
+        .await?;
+    iota_users::invalidate_user(id);
 
     Ok(())
 }
@@ -470,12 +510,15 @@ pub async fn change_iota_id(id: i64, new_iota_id: i64) -> Result<(), sqlx::Error
             .cloned()
             .expect("Database pool not initialized")
     };
+    let _timer = metrics::time_sql("change_iota_id");
 
     sqlx::query("UPDATE users SET iota_id = CAST(? AS UNSIGNED) WHERE id = CAST(? AS UNSIGNED)")
         .bind(new_iota_id)
         .bind(id)
         .execute(&pool)
         .await?;
+    iota_users::invalidate_user(id);
+    iota_users::invalidate(new_iota_id);
 
     Ok(())
 }
@@ -492,6 +535,7 @@ pub async fn change_keys(
             .cloned()
             .expect("Database pool not initialized")
     };
+    let _timer = metrics::time_sql("change_keys");
 
     sqlx::query(
         "UPDATE users SET public_key = ?, private_key_hash = ? WHERE id = CAST(? AS UNSIGNED)",
@@ -512,6 +556,7 @@ pub async fn change_token(id: i64, new_token: String) -> Result<(), sqlx::Error>
             .cloned()
             .expect("Database pool not initialized")
     };
+    let _timer = metrics::time_sql("change_token");
 
     sqlx::query("UPDATE users SET token = ? WHERE id = CAST(? AS UNSIGNED)")
         .bind(new_token)
@@ -535,6 +580,7 @@ pub async fn register_complete_user(
             .cloned()
             .expect("Database pool not initialized")
     };
+    let _timer = metrics::time_sql("register_complete_user");
 
     sqlx::query(
         "INSERT INTO users (id, username, public_key, iota_id, token) VALUES (?, ?, ?, ?, ?)",
@@ -546,49 +592,7 @@ pub async fn register_complete_user(
     .bind(token)
     .execute(&pool)
     .await?;
-
-    Ok(())
-}
-pub async fn print_users() -> Result<(), Box<dyn std::error::Error>> {
-    let pool = {
-        let db_lock = SQL_DB.read().await;
-        db_lock
-            .as_ref()
-            .cloned()
-            .expect("Database pool not initialized")
-    };
-
-    log!("Printing users...");
-    for row in sqlx::query(
-        "SELECT id, iota_id, username, display, status, about, sub_level, sub_end, public_key, private_key_hash, token FROM users",
-    )
-    .fetch_all(&pool)
-    .await?
-    .iter()
-    {
-        let id: i64 = row.get("id");
-        let iota_id: i64 = row.get("iota_id");
-        let username: String = row.get("username");
-        let display: Option<Vec<u8>> = row.get("display");
-        let status: Option<Vec<u8>> = row.get("status");
-        let about: Option<Vec<u8>> = row.get("about");
-        let sub_level: i32 = row.get("sub_level");
-        let sub_end: i64 = row.get("sub_end");
-
-        log!(
-            "User: {:?}",
-            (
-                id,
-                iota_id,
-                username,
-                display.map_or("".to_string(), |d| String::from_utf8_lossy(&d).to_string()),
-                status.map_or("".to_string(), |s| String::from_utf8_lossy(&s).to_string()),
-                about.map_or("".to_string(), |a| String::from_utf8_lossy(&a).to_string()),
-                sub_level,
-                sub_end
-            )
-        );
-    }
+    iota_users::invalidate(iota_id);
 
     Ok(())
 }
@@ -610,6 +614,7 @@ pub async fn register_complete_iota(id: i64, public_key: String) -> Result<(), s
             .cloned()
             .expect("Database pool not initialized")
     };
+    let _timer = metrics::time_sql("register_complete_iota");
 
     sqlx::query("INSERT INTO iotas (id, public_key) VALUES (?, ?)")
         .bind(id)
@@ -628,6 +633,7 @@ pub async fn get_iota_by_id(id: i64) -> Result<(i64, String), sqlx::Error> {
             .cloned()
             .expect("Database pool not initialized")
     };
+    let _timer = metrics::time_sql("get_iota_by_id");
 
     let result = sqlx::query_as::<_, (u64, Vec<u8>)>(
         "SELECT id, public_key FROM iotas WHERE id = CAST(? AS UNSIGNED)",
@@ -656,6 +662,7 @@ pub async fn change_iota_key(id: i64, new_key: String) -> Result<(), sqlx::Error
             .cloned()
             .expect("Database pool not initialized")
     };
+    let _timer = metrics::time_sql("change_iota_key");
 
     sqlx::query("UPDATE iotas SET public_key = ? WHERE id = CAST(? AS UNSIGNED)")
         .bind(new_key)
@@ -674,6 +681,7 @@ pub async fn delete_iota(id: i64) -> Result<(), sqlx::Error> {
             .cloned()
             .expect("Database pool not initialized")
     };
+    let _timer = metrics::time_sql("delete_iota");
 
     sqlx::query("DELETE FROM iotas WHERE id = CAST(? AS UNSIGNED)")
         .bind(id)
@@ -695,6 +703,7 @@ pub async fn get_omikron_by_id(id: i64) -> Result<(String, String), sqlx::Error>
             .cloned()
             .expect("Database pool not initialized")
     };
+    let _timer = metrics::time_sql("get_omikron_by_id");
 
     let row = sqlx::query_as::<_, (Vec<u8>, Vec<u8>)>(
         "SELECT public_key, ip_address FROM omikrons WHERE id = CAST(? AS UNSIGNED)",
@@ -712,6 +721,368 @@ pub async fn get_omikron_by_id(id: i64) -> Result<(String, String), sqlx::Error>
     }
 }
 
+pub async fn get_omikrons() -> Result<Vec<(i64, String, String, String)>, sqlx::Error> {
+    let pool = {
+        let db_lock = SQL_DB.read().await;
+        db_lock
+            .as_ref()
+            .cloned()
+            .expect("Database pool not initialized")
+    };
+    let _timer = metrics::time_sql("get_omikrons");
+
+    let rows = sqlx::query_as::<_, (u64, Vec<u8>, Vec<u8>, Vec<u8>)>(
+        "SELECT id, public_key, location, ip_address FROM omikrons ORDER BY id",
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(id, public_key, location, ip_address)| {
+            (
+                id as i64,
+                String::from_utf8_lossy(&public_key).to_string(),
+                String::from_utf8_lossy(&location).to_string(),
+                String::from_utf8_lossy(&ip_address).to_string(),
+            )
+        })
+        .collect())
+}
+
+pub async fn register_omikron(
+    id: i64,
+    public_key: String,
+    location: String,
+    ip_address: String,
+) -> Result<(), sqlx::Error> {
+    let pool = {
+        let db_lock = SQL_DB.read().await;
+        db_lock
+            .as_ref()
+            .cloned()
+            .expect("Database pool not initialized")
+    };
+    let _timer = metrics::time_sql("register_omikron");
+
+    sqlx::query("INSERT INTO omikrons (id, public_key, location, ip_address) VALUES (?, ?, ?, ?)")
+        .bind(id)
+        .bind(public_key)
+        .bind(location)
+        .bind(ip_address)
+        .execute(&pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn delete_omikron(id: i64) -> Result<bool, sqlx::Error> {
+    let pool = {
+        let db_lock = SQL_DB.read().await;
+        db_lock
+            .as_ref()
+            .cloned()
+            .expect("Database pool not initialized")
+    };
+    let _timer = metrics::time_sql("delete_omikron");
+
+    let result = sqlx::query("DELETE FROM omikrons WHERE id = CAST(? AS UNSIGNED)")
+        .bind(id)
+        .execute(&pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_omikron_location(id: i64) -> Result<String, sqlx::Error> {
+    let pool = {
+        let db_lock = SQL_DB.read().await;
+        db_lock
+            .as_ref()
+            .cloned()
+            .expect("Database pool not initialized")
+    };
+    let _timer = metrics::time_sql("get_omikron_location");
+
+    let row = sqlx::query_as::<_, (Vec<u8>,)>(
+        "SELECT location FROM omikrons WHERE id = CAST(? AS UNSIGNED)",
+    )
+    .bind(id)
+    .fetch_optional(&pool)
+    .await?;
+
+    match row {
+        Some((location,)) => Ok(String::from_utf8_lossy(&location).to_string()),
+        _ => Err(sqlx::Error::RowNotFound),
+    }
+}
+
+/// Registers an Omikron under a freshly generated id.
+pub async fn create_omikron(
+    public_key: String,
+    location: String,
+    ip_address: String,
+) -> Result<i64, sqlx::Error> {
+    let id = get_register_id().await as i64;
+    register_omikron(id, public_key, location, ip_address).await?;
+    Ok(id)
+}
+
+/// Returns the current public key and, while its overlap window is open, the
+/// key it replaced.
+pub async fn get_omikron_keys(id: i64) -> Result<(String, Option<String>), sqlx::Error> {
+    let (public_key, previous, _, _) = get_omikron_record(id).await?;
+    Ok((public_key, previous))
+}
+
+/// `(public_key, previous_public_key, location, ip_address)`, the previous key
+/// only while it is still accepted.
+pub async fn get_omikron_record(
+    id: i64,
+) -> Result<(String, Option<String>, String, String), sqlx::Error> {
+    let pool = {
+        let db_lock = SQL_DB.read().await;
+        db_lock
+            .as_ref()
+            .cloned()
+            .expect("Database pool not initialized")
+    };
+    let _timer = metrics::time_sql("get_omikron_record");
+
+    let row = sqlx::query_as::<_, (Vec<u8>, Option<Vec<u8>>, Option<u64>, Vec<u8>, Vec<u8>)>(
+        "SELECT public_key, previous_public_key, previous_key_expires, location, ip_address
+        FROM omikrons WHERE id = CAST(? AS UNSIGNED)",
+    )
+    .bind(id)
+    .fetch_optional(&pool)
+    .await?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    match row {
+        Some((public_key, previous, expires, location, ip_address)) => {
+            let previous = match (previous, expires) {
+                (Some(previous), Some(expires)) if expires > now => {
+                    Some(String::from_utf8_lossy(&previous).to_string())
+                }
+                _ => None,
+            };
+            Ok((
+                String::from_utf8_lossy(&public_key).to_string(),
+                previous,
+                String::from_utf8_lossy(&location).to_string(),
+                String::from_utf8_lossy(&ip_address).to_string(),
+            ))
+        }
+        _ => Err(sqlx::Error::RowNotFound),
+    }
+}
+
+/// Replaces the public key of an Omikron. The old key keeps working for
+/// `overlap_secs` so the Omikron can be restarted with the new key.
+pub async fn rotate_omikron_key(
+    id: i64,
+    new_public_key: String,
+    overlap_secs: u64,
+) -> Result<bool, sqlx::Error> {
+    let pool = {
+        let db_lock = SQL_DB.read().await;
+        db_lock
+            .as_ref()
+            .cloned()
+            .expect("Database pool not initialized")
+    };
+    let _timer = metrics::time_sql("rotate_omikron_key");
+
+    let expires = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        + overlap_secs;
+
+    let result = sqlx::query(
+        "UPDATE omikrons
+        SET previous_public_key = public_key, previous_key_expires = ?, public_key = ?
+        WHERE id = CAST(? AS UNSIGNED)",
+    )
+    .bind(expires)
+    .bind(new_public_key)
+    .bind(id)
+    .execute(&pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Fields that are `None` keep their current value.
+pub async fn update_omikron_address(
+    id: i64,
+    location: Option<String>,
+    ip_address: Option<String>,
+) -> Result<bool, sqlx::Error> {
+    let pool = {
+        let db_lock = SQL_DB.read().await;
+        db_lock
+            .as_ref()
+            .cloned()
+            .expect("Database pool not initialized")
+    };
+    let _timer = metrics::time_sql("update_omikron_address");
+
+    let result = sqlx::query(
+        "UPDATE omikrons
+        SET location = COALESCE(?, location), ip_address = COALESCE(?, ip_address)
+        WHERE id = CAST(? AS UNSIGNED)",
+    )
+    .bind(location)
+    .bind(ip_address)
+    .bind(id)
+    .execute(&pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+// ==========================================================================================
+//                                         PRESENCE
+// ==========================================================================================
+
+/// A `None` for `last_offline` keeps the stored value.
+pub async fn write_user_presence(
+    user_id: i64,
+    last_online: u64,
+    last_offline: Option<u64>,
+) -> Result<(), sqlx::Error> {
+    let pool = {
+        let db_lock = SQL_DB.read().await;
+        db_lock
+            .as_ref()
+            .cloned()
+            .expect("Database pool not initialized")
+    };
+    let _timer = metrics::time_sql("write_user_presence");
+
+    sqlx::query(
+        r#"
+        INSERT INTO user_presence (user_id, last_online, last_offline)
+        VALUES (?, ?, ?)
+        ON DUPLICATE KEY UPDATE
+            last_online = VALUES(last_online),
+            last_offline = COALESCE(VALUES(last_offline), last_offline)
+        "#,
+    )
+    .bind(user_id)
+    .bind(last_online)
+    .bind(last_offline)
+    .execute(&pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn write_iota_presence(
+    iota_id: i64,
+    last_online: u64,
+    last_offline: Option<u64>,
+) -> Result<(), sqlx::Error> {
+    let pool = {
+        let db_lock = SQL_DB.read().await;
+        db_lock
+            .as_ref()
+            .cloned()
+            .expect("Database pool not initialized")
+    };
+    let _timer = metrics::time_sql("write_iota_presence");
+
+    sqlx::query(
+        r#"
+        INSERT INTO iota_presence (iota_id, last_online, last_offline)
+        VALUES (?, ?, ?)
+        ON DUPLICATE KEY UPDATE
+            last_online = VALUES(last_online),
+            last_offline = COALESCE(VALUES(last_offline), last_offline)
+        "#,
+    )
+    .bind(iota_id)
+    .bind(last_online)
+    .bind(last_offline)
+    .execute(&pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Returns `(last_online, last_offline, hide_last_seen)`.
+pub async fn get_user_presence(
+    user_id: i64,
+) -> Result<(Option<u64>, Option<u64>, bool), sqlx::Error> {
+    let pool = {
+        let db_lock = SQL_DB.read().await;
+        db_lock
+            .as_ref()
+            .cloned()
+            .expect("Database pool not initialized")
+    };
+    let _timer = metrics::time_sql("get_user_presence");
+
+    let row = sqlx::query_as::<_, (Option<u64>, Option<u64>, bool)>(
+        "SELECT last_online, last_offline, hide_last_seen
+        FROM user_presence WHERE user_id = CAST(? AS UNSIGNED)",
+    )
+    .bind(user_id)
+    .fetch_optional(&pool)
+    .await?;
+
+    Ok(row.unwrap_or((None, None, false)))
+}
+
+pub async fn get_users_hiding_last_seen() -> Result<Vec<i64>, sqlx::Error> {
+    let pool = {
+        let db_lock = SQL_DB.read().await;
+        db_lock
+            .as_ref()
+            .cloned()
+            .expect("Database pool not initialized")
+    };
+    let _timer = metrics::time_sql("get_users_hiding_last_seen");
+
+    let rows = sqlx::query_as::<_, (u64,)>(
+        "SELECT user_id FROM user_presence WHERE hide_last_seen = TRUE",
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(rows.into_iter().map(|(id,)| id as i64).collect())
+}
+
+pub async fn set_hide_last_seen(user_id: i64, hide: bool) -> Result<(), sqlx::Error> {
+    let pool = {
+        let db_lock = SQL_DB.read().await;
+        db_lock
+            .as_ref()
+            .cloned()
+            .expect("Database pool not initialized")
+    };
+    let _timer = metrics::time_sql("set_hide_last_seen");
+
+    sqlx::query(
+        r#"
+        INSERT INTO user_presence (user_id, hide_last_seen)
+        VALUES (?, ?)
+        ON DUPLICATE KEY UPDATE hide_last_seen = VALUES(hide_last_seen)
+        "#,
+    )
+    .bind(user_id)
+    .bind(hide)
+    .execute(&pool)
+    .await?;
+
+    Ok(())
+}
+
 // ==========================================================================================
 //                                         PHI
 // ==========================================================================================
@@ -719,6 +1090,7 @@ pub async fn get_omikron_by_id(id: i64) -> Result<(String, String), sqlx::Error>
 pub async fn add_notification(sender_id: i64, receiver_id: i64) -> Result<(), sqlx::Error> {
     let db_lock = SQL_DB.read().await;
     let pool = db_lock.as_ref().expect("Database pool is not initialized");
+    let _timer = metrics::time_sql("add_notification");
 
     sqlx::query(
         r#"
@@ -737,6 +1109,7 @@ pub async fn add_notification(sender_id: i64, receiver_id: i64) -> Result<(), sq
 pub async fn read_notification(sender_id: i64, receiver_id: i64) -> Result<(), sqlx::Error> {
     let db_lock = SQL_DB.read().await;
     let pool = db_lock.as_ref().expect("Database pool is not initialized");
+    let _timer = metrics::time_sql("read_notification");
 
     sqlx::query(
         r#"
@@ -753,6 +1126,7 @@ pub async fn read_notification(sender_id: i64, receiver_id: i64) -> Result<(), s
 pub async fn get_notifications(user_id: i64) -> Result<Vec<(i64, i64)>, sqlx::Error> {
     let db_lock = SQL_DB.read().await;
     let pool = db_lock.as_ref().expect("Database pool is not initialized");
+    let _timer = metrics::time_sql("get_notifications");
 
     sqlx::query_as::<_, (i64, i64)>(
         r#"
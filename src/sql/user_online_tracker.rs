@@ -1,144 +1,708 @@
-use crate::sql;
 use crate::sql::connection_status::UserStatus;
-use dashmap::DashMap;
+use crate::sql::presence_store;
+use crate::transport::{omikron_manager, presence_subscriptions};
 use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::interval;
 
 #[derive(Debug, Clone)]
 pub struct UserConnection {
     pub connection_type: UserStatus,
     pub omikron_id: i64,
+    /// Unix seconds of the last connect, presence update or message sent on
+    /// behalf of the user
+    pub last_active: u64,
+    /// Set to `user_idle` by auto idle rather than by the user
+    pub auto_idle: bool,
 }
 
-// IotaID -> Primary OmikronID
-static IOTA_PRIMARY_OMIKRON_CONNECTION: Lazy<DashMap<i64, i64>> = Lazy::new(DashMap::new);
+/// Users per status, keyed by the status name.
+pub type StatusCounts = BTreeMap<String, usize>;
 
-// IotaID -> Vec<OmikronID>
-static IOTA_OMIKRON_CONNECTIONS: Lazy<DashMap<i64, Vec<i64>>> = Lazy::new(DashMap::new);
+/// What the tracker attributes to one Omikron.
+#[derive(Debug, Clone, Default)]
+pub struct OmikronCounts {
+    pub users_by_status: StatusCounts,
+    pub primary_iotas: usize,
+    pub secondary_iotas: usize,
+}
 
-// UserID -> UserStatus
-static USER_STATUS_MAP: Lazy<DashMap<i64, UserConnection>> = Lazy::new(DashMap::new);
+#[derive(Debug, Clone, Default)]
+pub struct TrackerCounts {
+    pub users_by_status: StatusCounts,
+    pub iotas: usize,
+    pub omikrons: BTreeMap<i64, OmikronCounts>,
+}
 
-pub fn track_iota_connection(iota_id: i64, omikron_id: i64, primary: bool) {
-    let mut entry = IOTA_OMIKRON_CONNECTIONS
-        .entry(iota_id)
-        .or_insert_with(Vec::new);
+// ============================================================================
+// State
+// ============================================================================
 
-    if !entry.contains(&omikron_id) {
-        entry.push(omikron_id);
-    }
+/// Everything the tracker knows, behind a single lock so that every update
+/// touching several maps is atomic. The reverse indexes make cleaning up
+/// after an Omikron or IOTA proportional to what it hosted.
+#[derive(Default)]
+struct TrackerState {
+    // UserID -> UserConnection
+    users: HashMap<i64, UserConnection>,
+    // IotaID -> OmikronIDs, oldest first. The first one is the primary.
+    iota_connections: HashMap<i64, Vec<i64>>,
+    // IotaID -> UserIDs registered on it, as loaded when it connected
+    iota_users: HashMap<i64, HashSet<i64>>,
+    // OmikronID -> IotaIDs connected through it
+    omikron_iotas: HashMap<i64, HashSet<i64>>,
+    // OmikronID -> UserIDs tracked on it
+    omikron_users: HashMap<i64, HashSet<i64>>,
+    // UserID -> Unix seconds the user was last seen online. Kept after
+    // untracking until the presence writer persisted it.
+    last_seen: HashMap<i64, u64>,
+}
 
-    if primary {
-        IOTA_PRIMARY_OMIKRON_CONNECTION.insert(iota_id, omikron_id);
+static STATE: Lazy<Mutex<TrackerState>> = Lazy::new(|| Mutex::new(TrackerState::default()));
+
+/// Notifications collected while the state is locked and sent once it is
+/// released, so that the receivers are free to read the tracker again.
+/// Every receiver does nothing until its part of the server was started,
+/// so the tracker also works without a runtime, e.g. in tests.
+#[derive(Default)]
+struct Effects {
+    status_changed: Vec<i64>,
+    users_seen: Vec<(i64, bool)>,
+    iotas_seen: Vec<(i64, bool)>,
+    primaries: Vec<(i64, Option<i64>, Option<i64>)>,
+}
+
+impl Effects {
+    fn apply(self) {
+        for (user_id, online) in self.users_seen {
+            presence_store::record_user(user_id, online);
+        }
+        for (iota_id, online) in self.iotas_seen {
+            presence_store::record_iota(iota_id, online);
+        }
+        for user_id in self.status_changed {
+            presence_subscriptions::status_changed(user_id);
+        }
+        for (iota_id, previous, primary) in self.primaries {
+            omikron_manager::primary_changed(iota_id, previous, primary);
+        }
     }
 }
 
-pub fn untrack_iota_connection(iota_id: i64, omikron_id: i64) -> bool {
-    let connections_empty = if let Some(r) = IOTA_OMIKRON_CONNECTIONS.get(&iota_id) {
-        let mut vec = r.value().clone();
-        vec.retain(|&id| id != omikron_id);
-        let empty = vec.is_empty();
-        drop(r);
-        IOTA_OMIKRON_CONNECTIONS.insert(iota_id, vec);
-        empty
-    } else {
-        false
+fn read<R>(f: impl FnOnce(&TrackerState) -> R) -> R {
+    let state = STATE.lock().unwrap_or_else(PoisonError::into_inner);
+    f(&state)
+}
+
+fn update<R>(f: impl FnOnce(&mut TrackerState, &mut Effects) -> R) -> R {
+    update_with(&STATE, f, Effects::apply)
+}
+
+/// `update` on any state, handing the collected effects to `apply`.
+fn update_with<R>(
+    state: &Mutex<TrackerState>,
+    f: impl FnOnce(&mut TrackerState, &mut Effects) -> R,
+    apply: impl FnOnce(Effects),
+) -> R {
+    let mut effects = Effects::default();
+    let result = {
+        let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+        f(&mut state, &mut effects)
     };
+    apply(effects);
+    result
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn is_online(status: &UserStatus) -> bool {
+    !matches!(
+        status,
+        UserStatus::user_offline | UserStatus::iota_offline | UserStatus::iota_borked
+    )
+}
+
+impl TrackerState {
+    fn set_user(
+        &mut self,
+        effects: &mut Effects,
+        user_id: i64,
+        status: UserStatus,
+        omikron_id: i64,
+    ) {
+        let now = now_secs();
+        if is_online(&status) {
+            self.last_seen.insert(user_id, now);
+            effects.users_seen.push((user_id, true));
+        }
+
+        let previous = self.users.insert(
+            user_id,
+            UserConnection {
+                connection_type: status.clone(),
+                omikron_id,
+                last_active: now,
+                auto_idle: false,
+            },
+        );
+        self.omikron_users
+            .entry(omikron_id)
+            .or_default()
+            .insert(user_id);
+
+        let changed = match &previous {
+            Some(previous) => {
+                if previous.omikron_id != omikron_id {
+                    self.unindex_user(user_id, previous.omikron_id);
+                }
+                if !is_online(&status) {
+                    self.record_last_seen(effects, user_id, previous);
+                }
+                previous.connection_type != status || previous.omikron_id != omikron_id
+            }
+            None => true,
+        };
+        if changed {
+            effects.status_changed.push(user_id);
+        }
+    }
+
+    fn remove_user(&mut self, effects: &mut Effects, user_id: i64) {
+        if let Some(previous) = self.users.remove(&user_id) {
+            self.unindex_user(user_id, previous.omikron_id);
+            self.record_last_seen(effects, user_id, &previous);
+            effects.status_changed.push(user_id);
+        }
+    }
 
-    if let Some(primary_ref) = IOTA_PRIMARY_OMIKRON_CONNECTION.get(&iota_id) {
-        let primary_id = *primary_ref.value();
-        drop(primary_ref);
-        if primary_id == omikron_id {
-            IOTA_PRIMARY_OMIKRON_CONNECTION.remove(&iota_id);
+    fn unindex_user(&mut self, user_id: i64, omikron_id: i64) {
+        if let Some(users) = self.omikron_users.get_mut(&omikron_id) {
+            users.remove(&user_id);
+            if users.is_empty() {
+                self.omikron_users.remove(&omikron_id);
+            }
+        }
+    }
+
+    /// Remembers when a user that is being untracked was last online.
+    fn record_last_seen(&mut self, effects: &mut Effects, user_id: i64, previous: &UserConnection) {
+        if is_online(&previous.connection_type) {
+            self.last_seen.insert(user_id, now_secs());
+            effects.users_seen.push((user_id, false));
         }
     }
 
-    if connections_empty {
-        IOTA_OMIKRON_CONNECTIONS.remove(&iota_id);
+    fn add_iota(&mut self, effects: &mut Effects, iota_id: i64, omikron_id: i64) {
+        let connections = self.iota_connections.entry(iota_id).or_default();
+        let previous = connections.first().copied();
+        if !connections.contains(&omikron_id) {
+            connections.push(omikron_id);
+        }
+        let primary = connections.first().copied();
+        self.omikron_iotas
+            .entry(omikron_id)
+            .or_default()
+            .insert(iota_id);
+
+        effects.iotas_seen.push((iota_id, true));
+        if previous != primary {
+            effects.primaries.push((iota_id, previous, primary));
+        }
+    }
+
+    /// When the last connection of the IOTA goes away its users are
+    /// untracked as well.
+    fn remove_iota(&mut self, effects: &mut Effects, iota_id: i64, omikron_id: i64) {
+        let Some(connections) = self.iota_connections.get_mut(&iota_id) else {
+            return;
+        };
+        let previous = connections.first().copied();
+        connections.retain(|&id| id != omikron_id);
+        let primary = connections.first().copied();
+
+        if let Some(iotas) = self.omikron_iotas.get_mut(&omikron_id) {
+            iotas.remove(&iota_id);
+            if iotas.is_empty() {
+                self.omikron_iotas.remove(&omikron_id);
+            }
+        }
+        if previous != primary {
+            effects.primaries.push((iota_id, previous, primary));
+        }
+
+        if primary.is_none() {
+            self.iota_connections.remove(&iota_id);
+            effects.iotas_seen.push((iota_id, false));
+            for user_id in self.iota_users.remove(&iota_id).unwrap_or_default() {
+                self.remove_user(effects, user_id);
+            }
+        }
     }
+}
+
+// ============================================================================
+// IOTAs
+// ============================================================================
 
-    connections_empty
+/// The oldest connection of an IOTA is its primary. A later connection never
+/// takes over, the next oldest is promoted when the primary goes away.
+/// `user_ids` are the users registered on the IOTA.
+pub fn track_iota_connection(iota_id: i64, omikron_id: i64, user_ids: &[i64]) {
+    update(|state, effects| {
+        state.add_iota(effects, iota_id, omikron_id);
+        state
+            .iota_users
+            .insert(iota_id, user_ids.iter().copied().collect());
+    });
+}
+
+/// Like `track_iota_connection`, and registered users the tracker does not
+/// know yet are tracked as `user_offline` on `omikron_id`. Users already
+/// tracked, e.g. online through the primary, are left alone.
+pub fn track_iota_connected(iota_id: i64, omikron_id: i64, user_ids: &[i64]) {
+    update(|state, effects| {
+        state.add_iota(effects, iota_id, omikron_id);
+        state
+            .iota_users
+            .insert(iota_id, user_ids.iter().copied().collect());
+        for user_id in user_ids {
+            if !state.users.contains_key(user_id) {
+                state.set_user(effects, *user_id, UserStatus::user_offline, omikron_id);
+            }
+        }
+    });
+}
+
+pub fn untrack_iota_connection(iota_id: i64, omikron_id: i64) {
+    update(|state, effects| state.remove_iota(effects, iota_id, omikron_id));
 }
 
 pub fn get_iota_primary_omikron_connection(iota_id: i64) -> Option<i64> {
-    IOTA_PRIMARY_OMIKRON_CONNECTION.get(&iota_id).map(|v| *v)
+    read(|state| state.iota_connections.get(&iota_id)?.first().copied())
 }
 
 pub fn get_iota_omikron_connections(iota_id: i64) -> Option<Vec<i64>> {
-    IOTA_OMIKRON_CONNECTIONS.get(&iota_id).map(|v| v.clone())
+    read(|state| state.iota_connections.get(&iota_id).cloned())
 }
 
+// ============================================================================
+// Users
+// ============================================================================
+
 pub fn track_user_status(user_id: i64, status: UserStatus, omikron_id: i64) {
-    USER_STATUS_MAP.insert(
-        user_id,
-        UserConnection {
-            connection_type: status,
-            omikron_id,
-        },
-    );
+    update(|state, effects| state.set_user(effects, user_id, status, omikron_id));
+}
+
+/// Sets the status of a user that is tracked on `omikron_id`, or not tracked
+/// at all and registered on one of its IOTAs. `false` if it is not this
+/// Omikron's user.
+pub fn track_own_user_status(user_id: i64, status: UserStatus, omikron_id: i64) -> bool {
+    update(|state, effects| {
+        let owned = match state.users.get(&user_id) {
+            Some(connection) => connection.omikron_id == omikron_id,
+            None => state
+                .omikron_iotas
+                .get(&omikron_id)
+                .into_iter()
+                .flatten()
+                .filter_map(|iota_id| state.iota_users.get(iota_id))
+                .any(|users| users.contains(&user_id)),
+        };
+        if owned {
+            state.set_user(effects, user_id, status, omikron_id);
+        }
+        owned
+    })
 }
 
 pub fn get_user_status(user_id: i64) -> Option<UserConnection> {
-    USER_STATUS_MAP.get(&user_id).map(|v| v.clone())
+    read(|state| state.users.get(&user_id).cloned())
 }
 
-pub fn untrack_many_users(user_ids: &[i64]) {
-    for user_id in user_ids {
-        USER_STATUS_MAP.remove(user_id);
-    }
+/// Unix seconds the user was last seen online.
+pub fn get_last_seen(user_id: i64) -> Option<u64> {
+    read(|state| state.last_seen.get(&user_id).copied())
 }
 
-pub async fn untrack_omikron(omikron_id: i64) {
-    let primary_keys_to_remove: Vec<i64> = IOTA_PRIMARY_OMIKRON_CONNECTION
-        .iter()
-        .filter(|entry| *entry.value() == omikron_id)
-        .map(|entry| *entry.key())
-        .collect();
+/// Records activity of a user tracked on `omikron_id`. A user that auto
+/// idle marked as `user_idle` is online again.
+pub fn touch_user(user_id: i64, omikron_id: i64) {
+    update(|state, effects| {
+        let Some(connection) = state.users.get_mut(&user_id) else {
+            return;
+        };
+        if connection.omikron_id != omikron_id {
+            return;
+        }
+        connection.last_active = now_secs();
+        if connection.auto_idle {
+            connection.auto_idle = false;
+            connection.connection_type = UserStatus::user_online;
+            effects.status_changed.push(user_id);
+        }
+    });
+}
+
+/// Called once `written` was persisted. Online users are kept, everyone
+/// else is read from the database from now on.
+pub fn forget_last_seen(user_id: i64, written: u64) {
+    update(|state, _| {
+        if state.users.contains_key(&user_id) {
+            return;
+        }
+        if state
+            .last_seen
+            .get(&user_id)
+            .is_some_and(|seen| *seen <= written)
+        {
+            state.last_seen.remove(&user_id);
+        }
+    });
+}
+
+/// Marks online users without activity for `idle_after` as `user_idle`.
+/// Activity is a connect, a presence update or any message an Omikron sends
+/// with the user as sender, see `touch_user`.
+pub fn start_auto_idle(idle_after: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = interval((idle_after / 4).max(Duration::from_secs(5)));
+        loop {
+            ticker.tick().await;
+            let cutoff = now_secs().saturating_sub(idle_after.as_secs());
+            update(|state, effects| {
+                for (user_id, connection) in state.users.iter_mut() {
+                    if connection.connection_type == UserStatus::user_online
+                        && connection.last_active < cutoff
+                    {
+                        connection.connection_type = UserStatus::user_idle;
+                        connection.auto_idle = true;
+                        effects.status_changed.push(*user_id);
+                    }
+                }
+            });
+        }
+    });
+}
+
+// ============================================================================
+// Omikrons
+// ============================================================================
+
+pub fn count_users_on_omikron(omikron_id: i64) -> usize {
+    read(|state| state.omikron_users.get(&omikron_id).map_or(0, HashSet::len))
+}
+
+/// Replaces what the tracker believes about `omikron_id` with its own
+/// snapshot of users and IOTAs, each IOTA with its registered users.
+/// Entries pointing at Omikrons for which `is_connected` is false are
+/// dropped for every IOTA in the snapshot.
+pub fn reconcile_omikron(
+    omikron_id: i64,
+    users: &[(i64, UserStatus)],
+    iotas: &[(i64, Vec<i64>)],
+    is_connected: impl Fn(i64) -> bool,
+) {
+    update(|state, effects| {
+        let snapshot_users: HashSet<i64> = users.iter().map(|(id, _)| *id).collect();
+        let stale_users: Vec<i64> = state
+            .omikron_users
+            .get(&omikron_id)
+            .map(|tracked| tracked.difference(&snapshot_users).copied().collect())
+            .unwrap_or_default();
+        for user_id in stale_users {
+            state.remove_user(effects, user_id);
+        }
+        for (user_id, status) in users {
+            state.set_user(effects, *user_id, status.clone(), omikron_id);
+        }
+
+        let snapshot_iotas: HashSet<i64> = iotas.iter().map(|(id, _)| *id).collect();
+        let stale_iotas: Vec<i64> = state
+            .omikron_iotas
+            .get(&omikron_id)
+            .map(|tracked| tracked.difference(&snapshot_iotas).copied().collect())
+            .unwrap_or_default();
+        for iota_id in stale_iotas {
+            state.remove_iota(effects, iota_id, omikron_id);
+        }
+        for (iota_id, user_ids) in iotas {
+            let gone: Vec<i64> = state
+                .iota_connections
+                .get(iota_id)
+                .into_iter()
+                .flatten()
+                .copied()
+                .filter(|id| *id != omikron_id && !is_connected(*id))
+                .collect();
+            for other in gone {
+                state.remove_iota(effects, *iota_id, other);
+            }
+            state.add_iota(effects, *iota_id, omikron_id);
+            state
+                .iota_users
+                .insert(*iota_id, user_ids.iter().copied().collect());
+        }
+    });
+}
 
-    for key in primary_keys_to_remove {
-        IOTA_PRIMARY_OMIKRON_CONNECTION.remove(&key);
+/// Drops every IOTA connection and user of an Omikron, promoting
+/// secondaries where it was the primary.
+pub fn untrack_omikron(omikron_id: i64) {
+    update(|state, effects| {
+        for iota_id in state.omikron_iotas.remove(&omikron_id).unwrap_or_default() {
+            state.remove_iota(effects, iota_id, omikron_id);
+        }
+        for user_id in state.omikron_users.remove(&omikron_id).unwrap_or_default() {
+            state.remove_user(effects, user_id);
+        }
+    });
+}
+
+/// Tracked users and IOTAs.
+pub fn totals() -> (usize, usize) {
+    read(|state| (state.users.len(), state.iota_connections.len()))
+}
+
+/// Aggregated counts, taken in one pass so they are consistent with each other.
+pub fn counts() -> TrackerCounts {
+    read(|state| {
+        let mut counts = TrackerCounts {
+            iotas: state.iota_connections.len(),
+            ..TrackerCounts::default()
+        };
+        for connection in state.users.values() {
+            let status = connection.connection_type.to_string();
+            *counts.users_by_status.entry(status.clone()).or_default() += 1;
+            *counts
+                .omikrons
+                .entry(connection.omikron_id)
+                .or_default()
+                .users_by_status
+                .entry(status)
+                .or_default() += 1;
+        }
+        for connections in state.iota_connections.values() {
+            for (position, omikron_id) in connections.iter().enumerate() {
+                let omikron = counts.omikrons.entry(*omikron_id).or_default();
+                if position == 0 {
+                    omikron.primary_iotas += 1;
+                } else {
+                    omikron.secondary_iotas += 1;
+                }
+            }
+        }
+        counts
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Checks that the reverse indexes match the maps they index.
+    fn assert_consistent(state: &TrackerState) {
+        for (iota_id, connections) in &state.iota_connections {
+            assert!(
+                !connections.is_empty(),
+                "IOTA {iota_id} without connections"
+            );
+            let unique: HashSet<_> = connections.iter().collect();
+            assert_eq!(
+                unique.len(),
+                connections.len(),
+                "IOTA {iota_id} listed twice"
+            );
+            for omikron_id in connections {
+                assert!(
+                    state
+                        .omikron_iotas
+                        .get(omikron_id)
+                        .is_some_and(|iotas| iotas.contains(iota_id)),
+                    "IOTA {iota_id} missing in omikron_iotas of {omikron_id}"
+                );
+            }
+        }
+        for (omikron_id, iotas) in &state.omikron_iotas {
+            assert!(!iotas.is_empty());
+            for iota_id in iotas {
+                assert!(
+                    state
+                        .iota_connections
+                        .get(iota_id)
+                        .is_some_and(|connections| connections.contains(omikron_id)),
+                    "omikron_iotas of {omikron_id} lists stale IOTA {iota_id}"
+                );
+            }
+        }
+        for (user_id, connection) in &state.users {
+            assert!(
+                state
+                    .omikron_users
+                    .get(&connection.omikron_id)
+                    .is_some_and(|users| users.contains(user_id)),
+                "user {user_id} missing in omikron_users"
+            );
+        }
+        for (omikron_id, users) in &state.omikron_users {
+            assert!(!users.is_empty());
+            for user_id in users {
+                assert_eq!(
+                    state.users.get(user_id).map(|c| c.omikron_id),
+                    Some(*omikron_id),
+                    "omikron_users of {omikron_id} lists stale user {user_id}"
+                );
+            }
+        }
     }
 
-    let mut offline_iotas = Vec::new();
-    let mut primary_to_remove = Vec::new();
+    // Tests share the global state, so each one uses its own id range.
 
-    // Collect iotas and primary info first
-    for r in IOTA_OMIKRON_CONNECTIONS.iter() {
-        let iota_id = *r.key();
-        let mut connections = r.value().clone();
-        connections.retain(|&id| id != omikron_id);
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_updates_keep_indexes_consistent() {
+        const BASE: i64 = 1_000_000;
+        let mut tasks = Vec::new();
+        for task in 0..64i64 {
+            tasks.push(tokio::spawn(async move {
+                let omikron_id = BASE + task % 8;
+                for round in 0..200i64 {
+                    let iota_id = BASE + (task + round) % 16;
+                    let users = [BASE + round % 32, BASE + 32 + task];
+                    match round % 5 {
+                        0 | 1 => track_iota_connection(iota_id, omikron_id, &users),
+                        2 => track_user_status(users[1], UserStatus::user_online, omikron_id),
+                        3 => untrack_iota_connection(iota_id, omikron_id),
+                        _ => untrack_omikron(omikron_id),
+                    }
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        read(assert_consistent);
 
-        if connections.is_empty() {
-            offline_iotas.push(iota_id);
+        for omikron_id in BASE..BASE + 8 {
+            untrack_omikron(omikron_id);
         }
+        read(|state| {
+            assert_consistent(state);
+            assert!((BASE..BASE + 16).all(|iota| !state.iota_connections.contains_key(&iota)));
+            assert!((BASE..BASE + 8).all(|omikron| !state.omikron_users.contains_key(&omikron)));
+        });
+    }
 
-        if IOTA_PRIMARY_OMIKRON_CONNECTION
-            .get(&iota_id)
-            .map(|p| *p == omikron_id)
-            .unwrap_or(false)
-        {
-            primary_to_remove.push(iota_id);
+    #[test]
+    fn next_oldest_connection_is_promoted() {
+        const IOTA: i64 = 2_000_000;
+        let (a, b, c) = (IOTA + 1, IOTA + 2, IOTA + 3);
+        for omikron_id in [a, b, c] {
+            track_iota_connection(IOTA, omikron_id, &[]);
         }
+        assert_eq!(get_iota_primary_omikron_connection(IOTA), Some(a));
+
+        untrack_iota_connection(IOTA, a);
+        assert_eq!(get_iota_primary_omikron_connection(IOTA), Some(b));
 
-        // Update the connections vector after filtering
-        IOTA_OMIKRON_CONNECTIONS.insert(iota_id, connections);
+        // A returning Omikron does not take over
+        track_iota_connection(IOTA, a, &[]);
+        untrack_omikron(b);
+        assert_eq!(get_iota_omikron_connections(IOTA), Some(vec![c, a]));
+
+        untrack_omikron(c);
+        untrack_omikron(a);
+        assert_eq!(get_iota_primary_omikron_connection(IOTA), None);
+        read(assert_consistent);
     }
 
-    // Step 2: Remove primary connections safely
-    for iota_id in primary_to_remove {
-        IOTA_PRIMARY_OMIKRON_CONNECTION.remove(&iota_id);
+    #[test]
+    fn promotion_and_last_disconnect_are_reported() {
+        const IOTA: i64 = 3_000_000;
+        let (a, b, user) = (IOTA + 1, IOTA + 2, IOTA + 3);
+        let state = Mutex::new(TrackerState::default());
+        let collected = Arc::new(Mutex::new(Vec::new()));
+        let run = |f: &dyn Fn(&mut TrackerState, &mut Effects)| {
+            let collected = collected.clone();
+            update_with(&state, f, move |effects| {
+                collected.lock().unwrap().push(effects)
+            })
+        };
+
+        run(&|state, effects| state.add_iota(effects, IOTA, a));
+        run(&|state, effects| {
+            state.add_iota(effects, IOTA, b);
+            state.iota_users.insert(IOTA, HashSet::from([user]));
+            state.set_user(effects, user, UserStatus::user_online, a);
+        });
+        run(&|state, effects| state.remove_iota(effects, IOTA, a));
+        run(&|state, effects| state.remove_iota(effects, IOTA, b));
+
+        let collected = collected.lock().unwrap();
+        assert_eq!(collected[0].primaries, vec![(IOTA, None, Some(a))]);
+        assert!(collected[1].primaries.is_empty());
+        assert_eq!(collected[2].primaries, vec![(IOTA, Some(a), Some(b))]);
+        assert_eq!(collected[3].primaries, vec![(IOTA, Some(b), None)]);
+        assert_eq!(collected[3].iotas_seen, vec![(IOTA, false)]);
+        assert_eq!(collected[3].users_seen, vec![(user, false)]);
+
+        let state = state.lock().unwrap();
+        assert!(state.users.is_empty());
+        assert_consistent(&state);
     }
 
-    // Step 3: Remove users that were on this omikron
-    USER_STATUS_MAP.retain(|_, status| status.omikron_id != omikron_id);
+    #[test]
+    fn connecting_secondary_keeps_users_online() {
+        const IOTA: i64 = 4_000_000;
+        let (a, b, online, unknown) = (IOTA + 1, IOTA + 2, IOTA + 3, IOTA + 4);
+        track_iota_connected(IOTA, a, &[online]);
+        track_user_status(online, UserStatus::user_online, a);
 
-    // Step 4: For offline iotas, remove associated users from USER_STATUS_MAP
-    for iota_id in offline_iotas {
-        if let Ok(users) = sql::sql::get_users_by_iota_id(iota_id).await {
-            for user in users {
-                USER_STATUS_MAP.remove(&user.0);
-            }
-        }
-        // Finally remove the empty connections vector
-        IOTA_OMIKRON_CONNECTIONS.remove(&iota_id);
+        track_iota_connected(IOTA, b, &[online, unknown]);
+        let online = get_user_status(online).unwrap();
+        assert_eq!(online.connection_type, UserStatus::user_online);
+        assert_eq!(online.omikron_id, a);
+        let unknown = get_user_status(unknown).unwrap();
+        assert_eq!(unknown.connection_type, UserStatus::user_offline);
+        assert_eq!(unknown.omikron_id, b);
+
+        untrack_omikron(a);
+        untrack_omikron(b);
+        read(assert_consistent);
+    }
+
+    #[test]
+    fn only_own_users_get_a_status() {
+        const IOTA: i64 = 5_000_000;
+        let (a, b, registered, stranger) = (IOTA + 1, IOTA + 2, IOTA + 3, IOTA + 4);
+        track_iota_connection(IOTA, a, &[registered]);
+
+        assert!(!track_own_user_status(stranger, UserStatus::user_online, a));
+        assert!(get_user_status(stranger).is_none());
+        assert!(!track_own_user_status(
+            registered,
+            UserStatus::user_online,
+            b
+        ));
+
+        assert!(track_own_user_status(registered, UserStatus::user_dnd, a));
+        assert_eq!(get_user_status(registered).unwrap().omikron_id, a);
+        // Tracked on A now, so B may not take it over by hosting the IOTA too
+        track_iota_connection(IOTA, b, &[registered]);
+        assert!(!track_own_user_status(
+            registered,
+            UserStatus::user_online,
+            b
+        ));
+
+        untrack_omikron(a);
+        untrack_omikron(b);
+        read(assert_consistent);
     }
 }
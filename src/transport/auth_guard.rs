@@ -0,0 +1,185 @@
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+// ============================================================================
+// Configuration
+// ============================================================================
+
+/// An Omikron has to finish the handshake within this time after connecting.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Failed handshakes allowed before backoff starts.
+const FREE_FAILURES: u32 = 3;
+/// The first backoff, doubled with every further failure.
+const BASE_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+/// Failures are forgotten after this long without a new one.
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+/// Entries are pruned once there are more than this many.
+const PRUNE_ABOVE: usize = 10_000;
+
+// ============================================================================
+// State
+// ============================================================================
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Subject {
+    Ip(IpAddr),
+    Omikron(i64),
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    blocked_until: Option<Instant>,
+}
+
+static FAILURES: Lazy<DashMap<Subject, Failures>> = Lazy::new(DashMap::new);
+
+fn subjects(ip: Option<IpAddr>, omikron_id: Option<i64>) -> impl Iterator<Item = Subject> {
+    ip.map(Subject::Ip)
+        .into_iter()
+        .chain(omikron_id.map(Subject::Omikron))
+}
+
+// ============================================================================
+// Limiter
+// ============================================================================
+
+/// `Err` with the remaining backoff if the address or the id is blocked.
+pub fn check(ip: Option<IpAddr>, omikron_id: Option<i64>) -> Result<(), Duration> {
+    let now = Instant::now();
+    let remaining = subjects(ip, omikron_id)
+        .filter_map(|subject| FAILURES.get(&subject)?.blocked_until)
+        .filter(|until| *until > now)
+        .map(|until| until - now)
+        .max();
+
+    match remaining {
+        Some(remaining) => Err(remaining),
+        None => Ok(()),
+    }
+}
+
+/// Only pass the id for failures after the peer answered the challenge,
+/// anyone can claim an id before that.
+pub fn record_failure(ip: Option<IpAddr>, omikron_id: Option<i64>) {
+    let now = Instant::now();
+    for subject in subjects(ip, omikron_id) {
+        let mut failures = FAILURES.entry(subject).or_insert(Failures {
+            count: 0,
+            last: now,
+            blocked_until: None,
+        });
+        if now.duration_since(failures.last) > FORGET_AFTER {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = now;
+        if failures.count > FREE_FAILURES {
+            failures.blocked_until = Some(now + backoff(failures.count - FREE_FAILURES));
+        }
+    }
+
+    if FAILURES.len() > PRUNE_ABOVE {
+        FAILURES.retain(|_, failures| now.duration_since(failures.last) <= FORGET_AFTER);
+    }
+}
+
+/// A successful handshake clears the failures of the address and the id.
+pub fn record_success(ip: Option<IpAddr>, omikron_id: i64) {
+    for subject in subjects(ip, Some(omikron_id)) {
+        FAILURES.remove(&subject);
+    }
+}
+
+fn backoff(excess: u32) -> Duration {
+    BASE_BACKOFF
+        .saturating_mul(1 << excess.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    // The failures are global, so every test uses its own address and id.
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        assert_eq!(backoff(1), BASE_BACKOFF);
+        assert_eq!(backoff(2), BASE_BACKOFF * 2);
+        assert_eq!(backoff(5), BASE_BACKOFF * 16);
+        assert_eq!(backoff(9), BASE_BACKOFF * 256);
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn blocks_after_the_free_failures() {
+        let ip = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        for _ in 0..FREE_FAILURES {
+            record_failure(ip, None);
+            assert!(check(ip, None).is_ok());
+        }
+
+        record_failure(ip, None);
+        let remaining = check(ip, None).unwrap_err();
+        assert!(remaining <= BASE_BACKOFF && remaining > Duration::ZERO);
+
+        record_failure(ip, None);
+        assert!(check(ip, None).unwrap_err() > BASE_BACKOFF);
+        // Another address is not affected
+        assert!(check(Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))), None).is_ok());
+    }
+
+    #[test]
+    fn address_failures_do_not_block_the_id() {
+        let omikron_id = Some(-4901);
+        // Failures before the proof are only recorded for the address
+        for last in 10..=10 + FREE_FAILURES as u8 {
+            let ip = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, last)));
+            for _ in 0..=FREE_FAILURES {
+                record_failure(ip, None);
+            }
+            assert!(check(ip, None).is_err());
+        }
+
+        let elsewhere = Some(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)));
+        assert!(check(elsewhere, omikron_id).is_ok());
+    }
+
+    #[test]
+    fn wrong_proofs_block_the_id_from_every_address() {
+        let omikron_id = Some(-4903);
+        for last in 30..=30 + FREE_FAILURES as u8 {
+            let ip = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, last)));
+            record_failure(ip, None);
+            record_failure(None, omikron_id);
+        }
+
+        let elsewhere = Some(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 2)));
+        assert!(check(elsewhere, None).is_ok());
+        assert!(check(elsewhere, omikron_id).is_err());
+    }
+
+    #[test]
+    fn success_clears_the_failures() {
+        let ip = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 100)));
+        let omikron_id = -4902;
+        for _ in 0..=FREE_FAILURES {
+            record_failure(ip, Some(omikron_id));
+        }
+        assert!(check(ip, Some(omikron_id)).is_err());
+
+        record_success(ip, omikron_id);
+        assert!(check(ip, Some(omikron_id)).is_ok());
+        // Counting starts over
+        record_failure(ip, Some(omikron_id));
+        assert!(check(ip, Some(omikron_id)).is_ok());
+    }
+}
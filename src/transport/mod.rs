@@ -1,2 +1,6 @@
+pub mod auth_guard;
+pub mod omikron_ca;
 pub mod omikron_connection;
 pub mod omikron_manager;
+pub mod presence_subscriptions;
+pub mod session_auth;
@@ -0,0 +1,172 @@
+use crate::config::{self, resolve_path};
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+    KeyUsagePurpose,
+};
+use rustls::{
+    RootCertStore,
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    server::WebPkiClientVerifier,
+};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use time::{Duration, OffsetDateTime};
+
+// ============================================================================
+// Configuration
+// ============================================================================
+
+/// Omikron certificates carry their id as `<omikron_id>.omikron` DNS SAN.
+const IDENTITY_SUFFIX: &str = "omikron";
+
+const CA_VALIDITY: Duration = Duration::days(3650);
+const CLIENT_VALIDITY: Duration = Duration::days(365);
+
+// ============================================================================
+// Error Types
+// ============================================================================
+
+#[derive(Debug, thiserror::Error)]
+pub enum OmikronCaError {
+    #[error("Omikron CA not found ({})", .0.display())]
+    MissingCa(PathBuf),
+    #[error("Omikron CA already exists")]
+    AlreadyExists,
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Certificate generation failed: {0}")]
+    Generate(#[from] rcgen::Error),
+    #[error("No client certificate presented")]
+    NoCertificate,
+    #[error("Client certificate rejected: {0}")]
+    Rejected(String),
+    #[error("Client certificate is not issued for Omikron {0}")]
+    IdentityMismatch(i64),
+}
+
+// ============================================================================
+// CA Management
+// ============================================================================
+
+fn ca_cert_path() -> PathBuf {
+    resolve_path(&config::get().transport.client_ca_cert)
+}
+
+fn ca_key_path() -> PathBuf {
+    resolve_path(&config::get().transport.client_ca_key)
+}
+
+pub fn load_ca_pem() -> Result<Vec<u8>, OmikronCaError> {
+    let path = ca_cert_path();
+    fs::read(&path).map_err(|_| OmikronCaError::MissingCa(path))
+}
+
+/// Creates the Omega-managed CA that signs Omikron client certificates.
+pub fn create_ca() -> Result<String, OmikronCaError> {
+    if ca_cert_path().exists() || ca_key_path().exists() {
+        return Err(OmikronCaError::AlreadyExists);
+    }
+
+    let key = KeyPair::generate()?;
+    let mut params = CertificateParams::new(Vec::<String>::new())?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "Tensamin Omega Omikron CA");
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    params.not_before = OffsetDateTime::now_utc();
+    params.not_after = OffsetDateTime::now_utc() + CA_VALIDITY;
+
+    let cert = params.self_signed(&key)?;
+
+    for path in [ca_key_path(), ca_cert_path()] {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    write_private_key(&ca_key_path(), &key.serialize_pem())?;
+    fs::write(ca_cert_path(), cert.pem())?;
+
+    Ok(cert.pem())
+}
+
+/// Writes a private key readable by the owner only, also when `path` exists.
+pub fn write_private_key(path: &Path, pem: &str) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    // The mode only applies to files that are created
+    #[cfg(unix)]
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(pem.as_bytes())
+}
+
+/// Issues a client certificate bound to `omikron_id`.
+/// Returns `(certificate_pem, private_key_pem)`.
+pub fn issue_certificate(omikron_id: i64) -> Result<(String, String), OmikronCaError> {
+    let ca_cert = String::from_utf8_lossy(&load_ca_pem()?).to_string();
+    let ca_key = fs::read(ca_key_path()).map_err(|_| OmikronCaError::MissingCa(ca_key_path()))?;
+    let ca_key = KeyPair::from_pem(&String::from_utf8_lossy(&ca_key))?;
+    let issuer = Issuer::from_ca_cert_pem(&ca_cert, ca_key)?;
+
+    let key = KeyPair::generate()?;
+    let mut params = CertificateParams::new(vec![identity_name(omikron_id)])?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, format!("Omikron {}", omikron_id));
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    params.not_before = OffsetDateTime::now_utc();
+    params.not_after = OffsetDateTime::now_utc() + CLIENT_VALIDITY;
+
+    let cert = params.signed_by(&key, &issuer)?;
+    Ok((cert.pem(), key.serialize_pem()))
+}
+
+// ============================================================================
+// Verification
+// ============================================================================
+
+fn identity_name(omikron_id: i64) -> String {
+    format!("{}.{}", omikron_id, IDENTITY_SUFFIX)
+}
+
+/// Checks that the presented chain was issued by the Omikron CA and that the
+/// leaf certificate belongs to `omikron_id`.
+pub fn verify_identity(chain: &[Vec<u8>], omikron_id: i64) -> Result<(), OmikronCaError> {
+    let (end_entity, intermediates) = chain.split_first().ok_or(OmikronCaError::NoCertificate)?;
+    let end_entity = CertificateDer::from(end_entity.as_slice());
+    let intermediates: Vec<CertificateDer<'_>> = intermediates
+        .iter()
+        .map(|c| CertificateDer::from(c.as_slice()))
+        .collect();
+
+    let mut roots = RootCertStore::empty();
+    let ca_pem = load_ca_pem()?;
+    for cert in rustls_pemfile::certs(&mut ca_pem.as_slice()) {
+        roots
+            .add(cert?)
+            .map_err(|e| OmikronCaError::Rejected(e.to_string()))?;
+    }
+
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .build()
+        .map_err(|e| OmikronCaError::Rejected(e.to_string()))?;
+    verifier
+        .verify_client_cert(&end_entity, &intermediates, UnixTime::now())
+        .map_err(|e| OmikronCaError::Rejected(e.to_string()))?;
+
+    let name = ServerName::try_from(identity_name(omikron_id))
+        .map_err(|_| OmikronCaError::IdentityMismatch(omikron_id))?;
+    webpki::EndEntityCert::try_from(&end_entity)
+        .and_then(|cert| cert.verify_is_valid_for_subject_name(&name))
+        .map_err(|_| OmikronCaError::IdentityMismatch(omikron_id))
+}
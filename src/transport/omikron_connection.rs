@@ -1,35 +1,77 @@
 use crate::{
-    get_private_key, get_public_key, log, log_cv_in, log_cv_out, log_err, log_in,
-    server::short_link::add_short_link,
+    config::{TransportConfig, resolve_path},
+    get_private_key, get_public_key, log, log_audit, log_cv_in, log_cv_out, log_err, log_in,
+    server::{
+        health::{self, Listener, ListenerState},
+        short_link::add_short_link,
+    },
     sql::{
         connection_status::UserStatus,
-        sql::{self, get_by_user_id, get_by_username, get_iota_by_id, get_omikron_by_id},
-        user_online_tracker::{self},
+        iota_users, presence_store,
+        sql::{self, get_by_user_id, get_by_username, get_iota_by_id, get_omikron_keys},
+        user_online_tracker::{self, StatusCounts},
+    },
+    transport::{
+        auth_guard, omikron_ca, omikron_manager, presence_subscriptions,
+        session_auth::{self, HandshakeKeys, Session},
+    },
+    util::{
+        crypto_helper::encrypt,
+        logger::PrintType,
+        metrics::{self, Direction},
+        shutdown,
     },
-    transport::omikron_manager,
-    util::{crypto_helper::encrypt, file_util::load_file_vec, logger::PrintType},
 };
 use base64::{Engine as _, engine::general_purpose::STANDARD};
 use dashmap::DashMap;
 use epsilon_core::{CommunicationType, CommunicationValue, DataTypes, DataValue};
 use epsilon_native::{Host, Receiver, Sender};
 use rand::{Rng, distributions::Alphanumeric};
+use sha2::{Digest, Sha256};
 use std::{
-    sync::Arc,
+    net::IpAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    },
     time::{Duration, Instant},
 };
-use tokio::{
-    sync::{Mutex, RwLock},
-    time::interval,
-};
+use tokio::sync::{Mutex, RwLock, oneshot, watch};
 use x448::PublicKey;
 
 // ============================================================================
 // Configuration
 // ============================================================================
 
-const CLEANUP_INTERVAL: Duration = Duration::from_secs(30);
-const MAX_WAITING_AGE: Duration = Duration::from_secs(60);
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// A state snapshot of a busy Omikron can take a while to build.
+const RESYNC_TIMEOUT: Duration = Duration::from_secs(30);
+/// Ids of Omega-initiated requests have the high bit set so they never
+/// collide with ids chosen by the Omikron.
+const REQUEST_ID_FLAG: u32 = 0x8000_0000;
+/// Omikrons report every 30 seconds, a report older than this means trouble.
+const STATS_STALE_AFTER: Duration = Duration::from_secs(90);
+
+/// When Omega pings a silent Omikron and when it gives up on it.
+#[derive(Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub missed_limit: u32,
+}
+
+impl Heartbeat {
+    fn from_config(config: &TransportConfig) -> Self {
+        Self {
+            interval: Duration::from_secs(config.heartbeat_interval_secs),
+            missed_limit: config.heartbeat_missed_limit,
+        }
+    }
+}
+
+/// Messages of every connection that are being handled right now.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+/// Connections whose receive loop or cleanup is still running.
+static OPEN: AtomicUsize = AtomicUsize::new(0);
 
 // ============================================================================
 // Error Types
@@ -45,21 +87,55 @@ pub enum OmikronError {
     InvalidResponse,
     #[error("Authentication failed")]
     AuthenticationFailed,
+    #[error("Client certificate rejected: {0}")]
+    ClientCertificate(String),
     #[error("SQL error: {0}")]
     Sql(String),
     #[error("Send error: {0}")]
     Send(String),
+    #[error("Request timed out")]
+    Timeout,
+}
+
+impl OmikronError {
+    /// Stable name of the variant, used as a metrics label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            OmikronError::NotConnected => "not_connected",
+            OmikronError::NotAuthenticated => "not_authenticated",
+            OmikronError::InvalidResponse => "invalid_response",
+            OmikronError::AuthenticationFailed => "authentication_failed",
+            OmikronError::ClientCertificate(_) => "client_certificate",
+            OmikronError::Sql(_) => "sql",
+            OmikronError::Send(_) => "send",
+            OmikronError::Timeout => "timeout",
+        }
+    }
 }
 
 pub type OmikronResult<T> = Result<T, OmikronError>;
 
 // ============================================================================
-// Waiting Task System (Preserved from original)
+// Omikron Stats
 // ============================================================================
 
-pub struct WaitingTask {
-    pub task: Box<dyn Fn(Arc<OmikronConnection>, CommunicationValue) -> bool + Send + Sync>,
-    pub inserted_at: Instant,
+/// The latest `omikron_stats` report of an Omikron.
+#[derive(Debug, Clone)]
+pub struct OmikronStats {
+    pub clients: i64,
+    pub iotas: i64,
+    /// 0-100, if the Omikron can measure it
+    pub cpu_usage: Option<i64>,
+    /// 0-100, if the Omikron can measure it
+    pub memory_usage: Option<i64>,
+    pub version: String,
+    pub received_at: Instant,
+}
+
+impl OmikronStats {
+    pub fn is_stale(&self) -> bool {
+        self.received_at.elapsed() > STATS_STALE_AFTER
+    }
 }
 
 // ============================================================================
@@ -98,12 +174,33 @@ impl AuthState {
 pub struct OmikronConnection {
     id: u64,
     sender: Mutex<Option<Sender>>,
+    require_client_cert: bool,
+    peer_certificates: Option<Vec<Vec<u8>>>,
     state: RwLock<AuthState>,
-    challenge: RwLock<String>,
+    /// SHA-256 of the outstanding challenge, taken by the first response
+    challenge_hash: Mutex<Option<[u8; 32]>>,
+    /// Derived with the challenge, taken by the first response as well
+    handshake_keys: Mutex<Option<HandshakeKeys>>,
+    /// Authenticates every message once the handshake is done
+    session: Mutex<Option<Session>>,
+    peer_ip: Option<IpAddr>,
     pub_key: RwLock<Option<Vec<u8>>>,
     pub ping: RwLock<i64>,
-    waiting_tasks: DashMap<u32, WaitingTask>,
-    cleanup_handle: Mutex<Option<tokio::task::JoinHandle<()>>>,
+    location: RwLock<String>,
+    draining: AtomicBool,
+    /// Connected clients as last reported by the Omikron, -1 until it reports
+    reported_load: AtomicI64,
+    stats: RwLock<Option<OmikronStats>>,
+    /// Omega-initiated requests waiting for their response
+    waiting_tasks: DashMap<u32, oneshot::Sender<CommunicationValue>>,
+    next_request_id: AtomicU32,
+    /// Id of the outstanding `state_snapshot` request, 0 if there is none
+    resync_request: AtomicU32,
+    connected_at: Instant,
+    /// Milliseconds after `connected_at` the last message arrived
+    last_received_ms: AtomicU64,
+    /// Set by `close`, ends the receive loop
+    closed: watch::Sender<bool>,
 }
 
 impl OmikronConnection {
@@ -111,16 +208,31 @@ impl OmikronConnection {
     // Construction
     // -------------------------------------------------------------------------
 
-    pub fn new(sender: Sender) -> Arc<Self> {
+    pub fn new(sender: Sender, require_client_cert: bool) -> Arc<Self> {
+        let peer_certificates = sender.peer_certificates();
+        let peer_ip = sender.peer_addr().map(|addr| addr.ip());
         let conn = Arc::new(Self {
             id: rand::random(),
             sender: Mutex::new(Some(sender)),
+            require_client_cert,
+            peer_certificates,
             state: RwLock::new(AuthState::Unauthenticated),
-            challenge: RwLock::new(String::new()),
+            challenge_hash: Mutex::new(None),
+            handshake_keys: Mutex::new(None),
+            session: Mutex::new(None),
+            peer_ip,
             pub_key: RwLock::new(None),
             ping: RwLock::new(-1),
+            location: RwLock::new(String::new()),
+            draining: AtomicBool::new(false),
+            reported_load: AtomicI64::new(-1),
+            stats: RwLock::new(None),
             waiting_tasks: DashMap::new(),
-            cleanup_handle: Mutex::new(None),
+            next_request_id: AtomicU32::new(0),
+            resync_request: AtomicU32::new(0),
+            connected_at: Instant::now(),
+            last_received_ms: AtomicU64::new(0),
+            closed: watch::channel(false).0,
         });
 
         conn
@@ -130,30 +242,65 @@ impl OmikronConnection {
     // Main Handler Loop
     // -------------------------------------------------------------------------
 
-    pub async fn handle(self: Arc<Self>, receiver: &mut Receiver) {
+    pub async fn handle(self: Arc<Self>, receiver: &mut Receiver, heartbeat: Heartbeat) {
         log_in!(
             self.id as i64,
             PrintType::Omega,
             "Omikron connection started"
         );
 
-        // Start cleanup task
-        let cleanup_conn = self.clone();
-        let cleanup_handle = tokio::spawn(async move {
-            let mut ticker = interval(CLEANUP_INTERVAL);
-            loop {
-                ticker.tick().await;
-                cleanup_conn
-                    .waiting_tasks
-                    .retain(|_, v| v.inserted_at.elapsed() < MAX_WAITING_AGE);
+        let mut closed = self.closed.subscribe();
+        let handshake_deadline = tokio::time::sleep(auth_guard::HANDSHAKE_TIMEOUT);
+        tokio::pin!(handshake_deadline);
+        let mut authenticated = false;
+        let mut ticker = tokio::time::interval_at(
+            tokio::time::Instant::now() + heartbeat.interval,
+            heartbeat.interval,
+        );
+        loop {
+            let cv = tokio::select! {
+                received = receiver.receive() => match received {
+                    Ok(cv) => cv,
+                    Err(_) => break,
+                },
+                _ = async { closed.wait_for(|closed| *closed).await.is_ok() } => break,
+                _ = &mut handshake_deadline, if !authenticated => {
+                    let omikron_id = self.state.read().await.omikron_id();
+                    self.handshake_failed(omikron_id, "handshake timed out");
+                    break;
+                }
+                _ = ticker.tick() => {
+                    if !self.clone().check_heartbeat(heartbeat).await {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            self.last_received_ms.store(
+                self.connected_at.elapsed().as_millis() as u64,
+                Ordering::Relaxed,
+            );
+            // Nothing new is started once shutdown waits for the running messages
+            if shutdown::is_shutting_down() {
+                continue;
             }
-        });
-        *self.cleanup_handle.lock().await = Some(cleanup_handle);
 
-        while let Ok(cv) = receiver.receive().await {
-            if let Err(e) = self.clone().process_message(cv).await {
+            IN_FLIGHT.fetch_add(1, Ordering::AcqRel);
+            let result = self.clone().process_message(cv).await;
+            IN_FLIGHT.fetch_sub(1, Ordering::AcqRel);
+            if !authenticated {
+                authenticated = self.state.read().await.is_authenticated();
+            }
+
+            if let Err(e) = result {
                 log_err!(0, PrintType::Omega, "Error processing message: {}", e);
-                if matches!(e, OmikronError::NotConnected) {
+                metrics::record_handler_error(e.kind());
+                if matches!(
+                    e,
+                    OmikronError::NotConnected
+                        | OmikronError::ClientCertificate(_)
+                        | OmikronError::AuthenticationFailed
+                ) {
                     break;
                 }
             }
@@ -167,20 +314,84 @@ impl OmikronConnection {
         );
     }
 
+    /// Pings an Omikron that has been silent for an interval. Returns `false`
+    /// once it missed `missed_limit` intervals and counts as dead.
+    async fn check_heartbeat(self: Arc<Self>, heartbeat: Heartbeat) -> bool {
+        let silent_for = self.silent_for();
+        if silent_for >= heartbeat.interval * heartbeat.missed_limit {
+            log_err!(
+                self.get_omikron_id().await.unwrap_or(0),
+                PrintType::Omega,
+                "No message for {}s, dropping the connection",
+                silent_for.as_secs()
+            );
+            return false;
+        }
+
+        if silent_for >= heartbeat.interval {
+            let ping =
+                CommunicationValue::new(CommunicationType::ping).with_id(self.new_request_id());
+            if let Err(OmikronError::NotConnected) = self.send(&ping).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Time since the last message of the Omikron, or since it connected.
+    fn silent_for(&self) -> Duration {
+        let last_received = Duration::from_millis(self.last_received_ms.load(Ordering::Relaxed));
+        self.connected_at.elapsed().saturating_sub(last_received)
+    }
+
     // -------------------------------------------------------------------------
     // Message Processing
     // -------------------------------------------------------------------------
 
     async fn process_message(self: Arc<Self>, cv: CommunicationValue) -> OmikronResult<()> {
-        if !cv.is_type(CommunicationType::pong) && !cv.is_type(CommunicationType::ping) {
+        metrics::record_message(Direction::In, &cv);
+        // A challenge response is the plaintext challenge
+        if !cv.is_type(CommunicationType::pong)
+            && !cv.is_type(CommunicationType::ping)
+            && !cv.is_type(CommunicationType::challenge_response)
+        {
             log_cv_in!(PrintType::Omikron, &cv);
         }
 
+        // Once the handshake is done, every message has to carry a valid MAC
+        let authentic = match self.session.lock().await.as_mut() {
+            Some(session) => session.verify(&cv),
+            None => true,
+        };
+        if !authentic {
+            log_audit!(
+                self.clone().get_omikron_id().await.unwrap_or(0),
+                "Message from {} failed authentication",
+                self.peer()
+            );
+            return Err(OmikronError::AuthenticationFailed);
+        }
+
         let msg_id = cv.get_id();
 
+        // Applied before the next message, which may already change what the
+        // snapshot describes
+        if msg_id != 0
+            && self
+                .resync_request
+                .compare_exchange(msg_id, 0, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            let omikron_id = self.state.read().await.omikron_id();
+            if let Some(omikron_id) = omikron_id {
+                self.apply_snapshot(omikron_id, cv).await;
+            }
+            return Ok(());
+        }
+
         // Check waiting tasks first (response to previous request)
-        if let Some((_, task)) = self.waiting_tasks.remove(&msg_id) {
-            let _ = (task.task)(self.clone(), cv);
+        if let Some((_, waiter)) = self.waiting_tasks.remove(&msg_id) {
+            let _ = waiter.send(cv);
             return Ok(());
         }
 
@@ -188,6 +399,10 @@ impl OmikronConnection {
         if cv.is_type(CommunicationType::ping) {
             return self.handle_ping(cv).await;
         }
+        // Answer to a heartbeat, receiving it is all that matters
+        if cv.is_type(CommunicationType::pong) {
+            return Ok(());
+        }
 
         let current_state = *self.state.read().await;
         // Route based on authentication state
@@ -205,118 +420,314 @@ impl OmikronConnection {
     // -------------------------------------------------------------------------
 
     async fn handle_unauthenticated(self: Arc<Self>, cv: CommunicationValue) -> OmikronResult<()> {
+        // Checked before anything costly is done for the attempt
+        if let Err(remaining) = auth_guard::check(self.peer_ip, None) {
+            return self.reject_backoff(cv.get_id(), 0, remaining).await;
+        }
+
         if !cv.is_type(CommunicationType::identification) {
+            self.handshake_failed(None, "expected an identification");
             let _ = self
                 .send_error_response(cv.get_id(), CommunicationType::error_not_authenticated)
                 .await;
             return Err(OmikronError::NotAuthenticated);
         }
 
-        // Extract omikron ID
-        let omikron_id = cv
-            .get_data(DataTypes::omikron_id)
-            .as_number()
-            .ok_or(OmikronError::InvalidResponse)?;
-        log!("Omikron {:?} connected", omikron_id);
-
-        // Lookup omikron in database
-        let (public_key, _) = get_omikron_by_id(omikron_id)
-            .await
-            .map_err(|e| OmikronError::Sql(e.to_string()))?;
-
-        log!("Got public Key");
+        let Some(omikron_id) = cv.get_data(DataTypes::omikron_id).as_number() else {
+            self.handshake_failed(None, "missing or malformed omikron id");
+            let _ = self
+                .send_error_response(cv.get_id(), CommunicationType::error_invalid_data)
+                .await;
+            return Err(OmikronError::AuthenticationFailed);
+        };
+        if let Err(remaining) = auth_guard::check(None, Some(omikron_id)) {
+            return self
+                .reject_backoff(cv.get_id(), omikron_id, remaining)
+                .await;
+        }
 
-        let pub_key_bytes = STANDARD
-            .decode(&public_key)
-            .map_err(|_| OmikronError::AuthenticationFailed)?;
+        let Some(omikron_nonce) = cv
+            .get_data(DataTypes::nonce)
+            .as_str()
+            .and_then(session_auth::decode_nonce)
+        else {
+            self.handshake_failed(Some(omikron_id), "missing or malformed nonce");
+            let _ = self
+                .send_error_response(cv.get_id(), CommunicationType::error_invalid_data)
+                .await;
+            return Err(OmikronError::AuthenticationFailed);
+        };
+
+        // The certificate has to belong to the claimed id before anything is looked up
+        if self.require_client_cert {
+            let chain = self.peer_certificates.as_deref().unwrap_or_default();
+            if let Err(e) = omikron_ca::verify_identity(chain, omikron_id) {
+                self.handshake_failed(
+                    Some(omikron_id),
+                    &format!("client certificate rejected: {}", e),
+                );
+                let _ = self
+                    .send_error_response(cv.get_id(), CommunicationType::error_not_authenticated)
+                    .await;
+                return Err(OmikronError::ClientCertificate(e.to_string()));
+            }
+        }
 
-        let pub_key_bytes_clone = pub_key_bytes.clone();
-        let omikron_pub_key = PublicKey::from_bytes(&pub_key_bytes_clone)
-            .ok_or(OmikronError::AuthenticationFailed)?;
+        let (current_key, previous_key) = match get_omikron_keys(omikron_id).await {
+            Ok(keys) => keys,
+            Err(sqlx::Error::RowNotFound) => {
+                self.handshake_failed(Some(omikron_id), "unknown Omikron");
+                let _ = self
+                    .send_error_response(cv.get_id(), CommunicationType::error_not_authenticated)
+                    .await;
+                return Err(OmikronError::AuthenticationFailed);
+            }
+            Err(e) => return Err(OmikronError::Sql(e.to_string())),
+        };
 
-        log!("Decoded public Key");
+        // While a rotation overlaps, an Omikron still running with its old key says so
+        let offered_key = cv.get_data(DataTypes::public_key).as_str();
+        let public_key = match previous_key {
+            Some(previous) if offered_key == Some(previous.as_str()) => previous,
+            _ => current_key,
+        };
 
-        // Generate challenge
+        let Some((pub_key_bytes, omikron_pub_key)) = STANDARD
+            .decode(&public_key)
+            .ok()
+            .and_then(|bytes| PublicKey::from_bytes(&bytes).map(|key| (bytes, key)))
+        else {
+            self.handshake_failed(Some(omikron_id), "registered public key is invalid");
+            return Err(OmikronError::AuthenticationFailed);
+        };
+
+        // Only the hash is kept, and only until the first response
         let challenge: String = rand::thread_rng()
             .sample_iter(&Alphanumeric)
             .take(32)
             .map(char::from)
             .collect();
+        let challenge_hash = Sha256::digest(challenge.as_bytes()).into();
+        let omega_nonce = session_auth::nonce();
 
-        log!("Generated Challenge");
-
-        *self.challenge.write().await = challenge.clone();
-
-        log!("Stored Challenge");
-
-        *self.pub_key.write().await = Some(pub_key_bytes);
-
-        log!("Stored Pubkey");
-
-        *self.state.write().await = AuthState::Identified { omikron_id };
-
-        log!("Stored State");
-
-        let challenge_clone = challenge.clone();
         let private_key = get_private_key();
-        let public_key_for_encrypt = omikron_pub_key;
-
-        let encrypted = tokio::task::spawn_blocking(move || {
-            encrypt(private_key, public_key_for_encrypt, &challenge_clone)
-                .map_err(|_| OmikronError::AuthenticationFailed)
+        let omega_pub_key = get_public_key();
+        let (encrypted, keys) = tokio::task::spawn_blocking(move || {
+            let keys = HandshakeKeys::derive(
+                &private_key,
+                &omikron_pub_key,
+                &omega_pub_key,
+                omikron_id,
+                &omikron_nonce,
+                &omega_nonce,
+            )
+            .ok_or(OmikronError::AuthenticationFailed)?;
+            let encrypted = encrypt(private_key, omikron_pub_key, &challenge)
+                .map_err(|_| OmikronError::AuthenticationFailed)?;
+            Ok::<_, OmikronError>((encrypted, keys))
         })
         .await
         .map_err(|_| OmikronError::AuthenticationFailed)??;
 
-        log!("Encrypted Challenge");
-
-        // Send challenge response
         let response = CommunicationValue::new(CommunicationType::challenge)
             .with_id(cv.get_id())
             .add_data(
                 DataTypes::public_key,
-                DataValue::Str(STANDARD.encode(get_public_key().as_bytes())),
+                DataValue::Str(STANDARD.encode(omega_pub_key.as_bytes())),
+            )
+            .add_data(DataTypes::challenge, DataValue::Str(encrypted))
+            .add_data(
+                DataTypes::nonce,
+                DataValue::Str(STANDARD.encode(omega_nonce)),
             )
-            .add_data(DataTypes::challenge, DataValue::Str(encrypted));
+            .add_data(DataTypes::proof, DataValue::Str(keys.omega_proof()));
 
-        log!("Sending Challenge");
+        *self.challenge_hash.lock().await = Some(challenge_hash);
+        *self.handshake_keys.lock().await = Some(keys);
+        *self.pub_key.write().await = Some(pub_key_bytes);
+        *self.state.write().await = AuthState::Identified { omikron_id };
         self.send(&response).await
     }
 
     async fn handle_identified(self: Arc<Self>, cv: CommunicationValue) -> OmikronResult<()> {
-        if !cv.is_type(CommunicationType::challenge_response) {
+        let omikron_id = self.state.read().await.omikron_id().unwrap_or(0);
+
+        // A challenge answers exactly one response, right or wrong
+        let expected = self.challenge_hash.lock().await.take();
+        let keys = self.handshake_keys.lock().await.take();
+        let is_response = cv.is_type(CommunicationType::challenge_response);
+        let response = is_response
+            .then(|| cv.get_data(DataTypes::challenge).as_str())
+            .flatten();
+        let proof = is_response
+            .then(|| cv.get_data(DataTypes::proof).as_str())
+            .flatten();
+
+        // The proof shows the Omikron holds its key and saw the same nonces
+        let keys = match (expected, response, keys, proof) {
+            (Some(expected), Some(response), Some(keys), Some(proof)) => {
+                let given: [u8; 32] = Sha256::digest(response.as_bytes()).into();
+                let challenge_matches = given
+                    .iter()
+                    .zip(expected.iter())
+                    .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+                    == 0;
+                (keys.verify_omikron_proof(proof) && challenge_matches).then_some(keys)
+            }
+            _ => None,
+        };
+        let Some(keys) = keys else {
+            *self.state.write().await = AuthState::Unauthenticated;
+            self.handshake_failed(Some(omikron_id), "wrong challenge response");
+            // Only a peer that got this far counts against the id
+            auth_guard::record_failure(None, Some(omikron_id));
             let _ = self
-                .send_error_response(cv.get_id(), CommunicationType::error_not_authenticated)
+                .send_error_response(cv.get_id(), CommunicationType::error_invalid_challenge)
                 .await;
-            return Err(OmikronError::NotAuthenticated);
+            return Err(OmikronError::AuthenticationFailed);
+        };
+
+        auth_guard::record_success(self.peer_ip, omikron_id);
+        log_audit!(omikron_id, "Omikron authenticated from {}", self.peer());
+        // Everything from the identification response on carries a MAC
+        *self.session.lock().await = Some(keys.into_session());
+        *self.state.write().await = AuthState::Authenticated { omikron_id };
+
+        if let Ok(location) = sql::get_omikron_location(omikron_id).await {
+            *self.location.write().await = location;
         }
+        omikron_manager::add_omikron(self.clone()).await;
 
-        let client_response = cv
-            .get_data(DataTypes::challenge)
-            .as_str()
-            .ok_or(OmikronError::InvalidResponse)?;
+        let response = CommunicationValue::new(CommunicationType::identification_response)
+            .with_id(cv.get_id())
+            .add_data(DataTypes::accepted, DataValue::Bool(true));
 
-        let expected_challenge = self.challenge.read().await.clone();
+        self.clone().send(&response).await?;
+        log_in!(omikron_id, PrintType::Omega, "Omikron authenticated");
 
-        if client_response == expected_challenge {
-            let omikron_id = self.state.read().await.omikron_id().unwrap_or(0);
-            *self.state.write().await = AuthState::Authenticated { omikron_id };
+        self.resync(omikron_id).await
+    }
 
-            omikron_manager::add_omikron(self.clone()).await;
+    /// Audits a failed handshake and counts it against the address and the id.
+    fn handshake_failed(&self, omikron_id: Option<i64>, reason: &str) {
+        log_audit!(
+            omikron_id.unwrap_or(0),
+            "Handshake from {} failed: {}",
+            self.peer(),
+            reason
+        );
+        // Anyone can claim an id before the proof, so only the address counts
+        auth_guard::record_failure(self.peer_ip, None);
+    }
 
-            let response = CommunicationValue::new(CommunicationType::identification_response)
-                .with_id(cv.get_id())
-                .add_data(DataTypes::accepted, DataValue::Bool(true));
+    async fn reject_backoff(
+        self: Arc<Self>,
+        message_id: u32,
+        omikron_id: i64,
+        remaining: Duration,
+    ) -> OmikronResult<()> {
+        log_audit!(
+            omikron_id,
+            "Handshake from {} rejected, backing off for {}s",
+            self.peer(),
+            remaining.as_secs()
+        );
+        let _ = self
+            .send_error_response(message_id, CommunicationType::error_not_authenticated)
+            .await;
+        Err(OmikronError::AuthenticationFailed)
+    }
 
-            self.clone().send(&response).await?;
-            log_in!(omikron_id, PrintType::Omega, "Omikron authenticated");
-            Ok(())
-        } else {
-            let _ = self
-                .send_error_response(cv.get_id(), CommunicationType::error_invalid_challenge)
-                .await;
-            Err(OmikronError::AuthenticationFailed)
+    fn peer(&self) -> String {
+        self.peer_ip
+            .map_or_else(|| "an unknown address".to_string(), |ip| ip.to_string())
+    }
+
+    /// Asks a freshly authenticated Omikron for everything it hosts. The
+    /// answer is applied by the receive loop before any later message, see
+    /// `apply_snapshot`.
+    async fn resync(self: Arc<Self>, omikron_id: i64) -> OmikronResult<()> {
+        let id = self.new_request_id();
+        self.resync_request.store(id, Ordering::Release);
+        let request = CommunicationValue::new(CommunicationType::state_snapshot).with_id(id);
+        self.clone().send(&request).await?;
+
+        tokio::spawn(async move {
+            tokio::time::sleep(RESYNC_TIMEOUT).await;
+            if self
+                .resync_request
+                .compare_exchange(id, 0, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                log_err!(
+                    omikron_id,
+                    PrintType::Omega,
+                    "Resync failed: {}",
+                    OmikronError::Timeout
+                );
+            }
+        });
+        Ok(())
+    }
+
+    /// Makes the tracker match a state snapshot. Runs inside the receive loop,
+    /// so the messages that follow it are applied on top of it.
+    async fn apply_snapshot(self: Arc<Self>, omikron_id: i64, snapshot: CommunicationValue) {
+        if !snapshot.is_type(CommunicationType::state_snapshot) {
+            log_err!(
+                omikron_id,
+                PrintType::Omega,
+                "Unexpected resync answer: {:?}",
+                snapshot.get_type()
+            );
+            return;
         }
+
+        let mut users = Vec::new();
+        if let DataValue::Array(entries) = snapshot.get_data(DataTypes::users) {
+            for entry in entries {
+                let DataValue::Container(fields) = entry else {
+                    continue;
+                };
+                let user_id =
+                    container_value(fields, DataTypes::user_id).and_then(DataValue::as_number);
+                let status = container_value(fields, DataTypes::online_status)
+                    .and_then(DataValue::as_str)
+                    .and_then(UserStatus::from_str);
+                if let (Some(user_id), Some(status)) = (user_id, status) {
+                    users.push((user_id, status));
+                }
+            }
+        }
+
+        let mut iotas = Vec::new();
+        if let DataValue::Array(entries) = snapshot.get_data(DataTypes::iota_ids) {
+            for entry in entries {
+                let DataValue::Container(fields) = entry else {
+                    continue;
+                };
+                // The primary is elected here, whatever the Omikron believes
+                if let Some(iota_id) =
+                    container_value(fields, DataTypes::iota_id).and_then(DataValue::as_number)
+                {
+                    iotas.push((iota_id, iota_user_ids(iota_id).await));
+                }
+            }
+        }
+
+        user_online_tracker::reconcile_omikron(
+            omikron_id,
+            &users,
+            &iotas,
+            omikron_manager::is_connected,
+        );
+        log_in!(
+            omikron_id,
+            PrintType::Omega,
+            "Resynced {} users and {} IOTAs",
+            users.len(),
+            iotas.len()
+        );
     }
 
     // -------------------------------------------------------------------------
@@ -328,6 +739,11 @@ impl OmikronConnection {
         cv: CommunicationValue,
         omikron_id: i64,
     ) -> OmikronResult<()> {
+        // Whatever an Omikron sends on behalf of a user counts as activity
+        if cv.get_sender() != 0 {
+            user_online_tracker::touch_user(cv.get_sender() as i64, omikron_id);
+        }
+
         match cv.get_type() {
             // Link shortening
             CommunicationType::shorten_link => self.handle_shorten_link(cv).await,
@@ -349,10 +765,27 @@ impl OmikronConnection {
                 self.handle_iota_disconnected(cv, omikron_id).await;
                 Ok(())
             }
+            CommunicationType::set_user_presence => {
+                self.handle_set_user_presence(cv, omikron_id).await
+            }
             CommunicationType::sync_client_iota_status => {
                 self.handle_sync_status(cv, omikron_id).await;
                 Ok(())
             }
+            CommunicationType::route_message => self.handle_route_message(cv, omikron_id).await,
+            CommunicationType::subscribe_presence => {
+                self.handle_presence_subscription(cv, omikron_id, true)
+                    .await
+            }
+            CommunicationType::unsubscribe_presence => {
+                self.handle_presence_subscription(cv, omikron_id, false)
+                    .await
+            }
+            CommunicationType::omikron_stats => {
+                self.handle_omikron_stats(cv).await;
+                Ok(())
+            }
+            CommunicationType::topology => self.handle_topology(cv).await,
 
             CommunicationType::get_user_data => self.handle_get_user_data(cv).await,
             CommunicationType::get_iota_data => self.handle_get_iota_data(cv).await,
@@ -431,25 +864,55 @@ impl OmikronConnection {
         }
     }
 
+    async fn handle_set_user_presence(
+        self: Arc<Self>,
+        cv: CommunicationValue,
+        omikron_id: i64,
+    ) -> OmikronResult<()> {
+        let user_id = cv.get_sender() as i64;
+        let status = cv
+            .get_data(DataTypes::online_status)
+            .as_str()
+            .and_then(UserStatus::from_str);
+
+        // Only the user states can be set, IOTA states follow the IOTA connection
+        let Some(status) = status.filter(UserStatus::is_user_status) else {
+            return self
+                .send_error_response(cv.get_id(), CommunicationType::error_invalid_data)
+                .await;
+        };
+        if user_id == 0 {
+            return self
+                .send_error_response(cv.get_id(), CommunicationType::error_invalid_data)
+                .await;
+        }
+
+        // Users of another Omikron or of no IOTA hosted here are not this one's to change
+        if !user_online_tracker::track_own_user_status(user_id, status, omikron_id) {
+            return self
+                .send_error_response(cv.get_id(), CommunicationType::error_not_found)
+                .await;
+        }
+
+        let response = CommunicationValue::new(CommunicationType::success).with_id(cv.get_id());
+        self.send(&response).await
+    }
+
     async fn handle_iota_connected(self: Arc<Self>, cv: CommunicationValue, omikron_id: i64) {
         log_in!(PrintType::Omega, "IOTA connected");
         if let Some(iota_id) = cv.get_data(DataTypes::iota_id).as_number() {
             let iota_id = iota_id as i64;
-            user_online_tracker::track_iota_connection(iota_id, omikron_id, true);
 
-            let mut user_ids = Vec::new();
-            if let Ok(users) = sql::get_users_by_iota_id(iota_id).await {
-                for (user_id, _, _, _, _, _, _, _, _, _, _, _) in users {
-                    user_ids.push(DataValue::Number(user_id));
-                    user_online_tracker::track_user_status(
-                        user_id,
-                        UserStatus::user_offline,
-                        omikron_id,
-                    );
+            let user_ids = match iota_users::user_ids(iota_id).await {
+                Ok(user_ids) => user_ids,
+                Err(_) => {
+                    log_in!(PrintType::General, "SQL error loading users for IOTA");
+                    Vec::new()
                 }
-            } else {
-                log_in!(PrintType::General, "SQL error loading users for IOTA");
-            }
+            };
+
+            user_online_tracker::track_iota_connected(iota_id, omikron_id, &user_ids);
+            let user_ids = user_ids.into_iter().map(DataValue::Number).collect();
 
             let response = CommunicationValue::new(CommunicationType::iota_user_data)
                 .with_id(cv.get_id())
@@ -465,13 +928,7 @@ impl OmikronConnection {
         log_in!(PrintType::Omega, "IOTA disconnected");
         if let Some(iota_id) = cv.get_data(DataTypes::iota_id).as_number() {
             let iota_id = iota_id as i64;
-            let iota_offline = user_online_tracker::untrack_iota_connection(iota_id, omikron_id);
-            if iota_offline {
-                if let Ok(users) = sql::get_users_by_iota_id(iota_id).await {
-                    let user_ids: Vec<i64> = users.iter().map(|u| u.0).collect();
-                    user_online_tracker::untrack_many_users(&user_ids);
-                }
-            }
+            user_online_tracker::untrack_iota_connection(iota_id, omikron_id);
         }
     }
 
@@ -491,12 +948,159 @@ impl OmikronConnection {
         if let DataValue::Array(iota_ids) = cv.get_data(DataTypes::iota_ids) {
             for iota_id_val in iota_ids {
                 if let DataValue::Number(iota_id) = iota_id_val {
-                    user_online_tracker::track_iota_connection(*iota_id, omikron_id, true);
+                    let user_ids = iota_user_ids(*iota_id).await;
+                    user_online_tracker::track_iota_connection(*iota_id, omikron_id, &user_ids);
                 }
             }
         }
     }
 
+    /// Forwards `payload` to the Omikron hosting the addressed user or IOTA
+    /// and tells the sender whether it was delivered.
+    async fn handle_route_message(
+        self: Arc<Self>,
+        cv: CommunicationValue,
+        omikron_id: i64,
+    ) -> OmikronResult<()> {
+        let user_id = cv.get_data(DataTypes::user_id).as_number();
+        let iota_id = cv.get_data(DataTypes::iota_id).as_number();
+        let payload = cv.get_data(DataTypes::payload);
+
+        if (user_id.is_none() && iota_id.is_none()) || matches!(payload, DataValue::Null) {
+            return self
+                .send_error_response(cv.get_id(), CommunicationType::error_invalid_data)
+                .await;
+        }
+
+        let Some(target) = omikron_manager::resolve_route(user_id, iota_id) else {
+            return self
+                .send_error_response(cv.get_id(), CommunicationType::error_not_found)
+                .await;
+        };
+
+        let mut forward = CommunicationValue::new(CommunicationType::route_message)
+            .with_sender(cv.get_sender())
+            .add_data(DataTypes::omikron_id, DataValue::Number(omikron_id))
+            .add_data(DataTypes::payload, payload.clone());
+        if let Some(user_id) = user_id {
+            forward = forward.add_data(DataTypes::user_id, DataValue::Number(user_id));
+        }
+        if let Some(iota_id) = iota_id {
+            forward = forward.add_data(DataTypes::iota_id, DataValue::Number(iota_id));
+        }
+
+        match target.send_message(&forward).await {
+            Ok(()) => {
+                let response =
+                    CommunicationValue::new(CommunicationType::success).with_id(cv.get_id());
+                self.send(&response).await
+            }
+            Err(e) => {
+                let response = CommunicationValue::new(CommunicationType::error)
+                    .with_id(cv.get_id())
+                    .add_data(DataTypes::error_type, DataValue::Str(e.to_string()));
+                self.send(&response).await
+            }
+        }
+    }
+
+    async fn handle_presence_subscription(
+        self: Arc<Self>,
+        cv: CommunicationValue,
+        omikron_id: i64,
+        subscribe: bool,
+    ) -> OmikronResult<()> {
+        let DataValue::Array(user_ids) = cv.get_data(DataTypes::user_ids) else {
+            return self
+                .send_error_response(cv.get_id(), CommunicationType::error_invalid_data)
+                .await;
+        };
+        let user_ids: Vec<i64> = user_ids.iter().filter_map(DataValue::as_number).collect();
+
+        if subscribe {
+            presence_subscriptions::subscribe(omikron_id, &user_ids);
+        } else {
+            presence_subscriptions::unsubscribe(omikron_id, &user_ids);
+        }
+
+        let response = CommunicationValue::new(CommunicationType::success).with_id(cv.get_id());
+        self.send(&response).await
+    }
+
+    async fn handle_omikron_stats(self: Arc<Self>, cv: CommunicationValue) {
+        let (Some(clients), Some(iotas)) = (
+            cv.get_data(DataTypes::clients).as_number(),
+            cv.get_data(DataTypes::iotas).as_number(),
+        ) else {
+            log_err!(0, PrintType::Omega, "Incomplete omikron_stats report");
+            return;
+        };
+
+        let stats = OmikronStats {
+            clients,
+            iotas,
+            cpu_usage: cv.get_data(DataTypes::cpu_usage).as_number(),
+            memory_usage: cv.get_data(DataTypes::memory_usage).as_number(),
+            version: cv
+                .get_data(DataTypes::version)
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            received_at: Instant::now(),
+        };
+
+        self.reported_load.store(clients, Ordering::Relaxed);
+        *self.stats.write().await = Some(stats);
+    }
+
+    async fn handle_topology(self: Arc<Self>, cv: CommunicationValue) -> OmikronResult<()> {
+        let topology = omikron_manager::topology();
+
+        let omikrons = topology
+            .omikrons
+            .iter()
+            .map(|omikron| {
+                let mut fields = vec![
+                    (DataTypes::omikron_id, DataValue::Number(omikron.id)),
+                    (
+                        DataTypes::connected,
+                        DataValue::Bool(omikron.connected_for.is_some()),
+                    ),
+                    (DataTypes::draining, DataValue::Bool(omikron.draining)),
+                    (
+                        DataTypes::users,
+                        status_counts_value(&omikron.counts.users_by_status),
+                    ),
+                    (
+                        DataTypes::primary_iotas,
+                        DataValue::Number(omikron.counts.primary_iotas as i64),
+                    ),
+                    (
+                        DataTypes::secondary_iotas,
+                        DataValue::Number(omikron.counts.secondary_iotas as i64),
+                    ),
+                ];
+                if let Some(connected_for) = omikron.connected_for {
+                    fields.push((
+                        DataTypes::connected_secs,
+                        DataValue::Number(connected_for.as_secs() as i64),
+                    ));
+                }
+                DataValue::Container(fields)
+            })
+            .collect();
+
+        let response = CommunicationValue::new(CommunicationType::topology)
+            .with_id(cv.get_id())
+            .add_data(
+                DataTypes::users,
+                status_counts_value(&topology.users_by_status),
+            )
+            .add_data(DataTypes::iotas, DataValue::Number(topology.iotas as i64))
+            .add_data(DataTypes::omikrons, DataValue::Array(omikrons));
+        self.send(&response).await
+    }
+
     async fn handle_get_user_data(self: Arc<Self>, cv: CommunicationValue) -> OmikronResult<()> {
         // Try by user_id first
         if let Some(user_id) = cv.get_data(DataTypes::user_id).as_number() {
@@ -600,6 +1204,9 @@ impl OmikronConnection {
                 DataValue::Str(UserStatus::iota_offline.to_string()),
             );
         }
+        if let Some(last_seen) = presence_store::last_seen(id).await {
+            response = response.add_data(DataTypes::last_seen, DataValue::Number(last_seen as i64));
+        }
 
         response = response.add_data(
             DataTypes::omikron_connections,
@@ -847,6 +1454,12 @@ impl OmikronConnection {
                 error_message = e.to_string();
             }
         }
+        if let Some(hide) = cv.get_data(DataTypes::hide_last_seen).as_bool()
+            && let Err(e) = presence_store::set_hidden(user_id, hide).await
+        {
+            success = false;
+            error_message = e.to_string();
+        }
         if let (Some(public_key), Some(private_key_hash)) = (
             cv.get_data(DataTypes::public_key).as_str(),
             cv.get_data(DataTypes::private_key_hash).as_str(),
@@ -1047,6 +1660,9 @@ impl OmikronConnection {
         if let DataValue::Number(last_ping) = cv.get_data(DataTypes::last_ping) {
             *self.ping.write().await = *last_ping;
         }
+        if let Some(load) = cv.get_data(DataTypes::load).as_number() {
+            self.reported_load.store(load, Ordering::Relaxed);
+        }
 
         let response = CommunicationValue::new(CommunicationType::pong).with_id(cv.get_id());
         self.send(&response).await
@@ -1064,10 +1680,14 @@ impl OmikronConnection {
         let guard = self.sender.lock().await;
         let sender = guard.as_ref().ok_or(OmikronError::NotConnected)?;
 
+        // Signed under the sender lock so the counter follows the send order
+        let signed = self.session.lock().await.as_mut().map(|s| s.sign(cv));
         sender
-            .send(cv)
+            .send(signed.as_ref().unwrap_or(cv))
             .await
-            .map_err(|e| OmikronError::Send(e.to_string()))
+            .map_err(|e| OmikronError::Send(e.to_string()))?;
+        metrics::record_message(Direction::Out, cv);
+        Ok(())
     }
 
     async fn send_error_response(
@@ -1079,7 +1699,11 @@ impl OmikronConnection {
         self.send(&error).await
     }
 
+    /// Drops the sender and ends the receive loop, which drops the receiver
+    /// and with it the transport. Cleanup runs as for any other disconnect.
     pub async fn close(self: Arc<Self>) {
+        self.sender.lock().await.take();
+        self.closed.send_replace(true);
         log_in!(
             self.get_omikron_id().await.unwrap_or(0),
             PrintType::Omega,
@@ -1088,18 +1712,24 @@ impl OmikronConnection {
     }
 
     async fn cleanup(self: Arc<Self>) {
+        self.sender.lock().await.take();
+
         if let Some(omikron_id) = self.state.read().await.omikron_id() {
             if omikron_id != 0 {
                 log_in!(omikron_id, PrintType::Omega, "Omikron disconnected");
-                omikron_manager::remove_omikron(omikron_id).await;
-                user_online_tracker::untrack_omikron(omikron_id).await;
+                // A replaced or decommissioned session no longer owns anything
+                if omikron_manager::remove_omikron(omikron_id, &self) {
+                    presence_subscriptions::remove_omikron(omikron_id);
+                    // A draining Omikron is untracked once its drain deadline passes
+                    if !self.is_draining() {
+                        user_online_tracker::untrack_omikron(omikron_id);
+                    }
+                }
             }
         }
 
-        // Cancel cleanup task
-        if let Some(handle) = self.cleanup_handle.lock().await.take() {
-            handle.abort();
-        }
+        // Pending requests fail with NotConnected
+        self.waiting_tasks.clear();
     }
 
     // Public API for external use
@@ -1114,26 +1744,167 @@ impl OmikronConnection {
     pub async fn send_message(self: Arc<Self>, cv: &CommunicationValue) -> OmikronResult<()> {
         self.send(cv).await
     }
+
+    /// A fresh id for a message Omega initiates.
+    fn new_request_id(&self) -> u32 {
+        REQUEST_ID_FLAG | (self.next_request_id.fetch_add(1, Ordering::Relaxed) & !REQUEST_ID_FLAG)
+    }
+
+    /// Sends `cv` with a fresh message id and waits for the Omikron's answer.
+    pub async fn request(
+        self: Arc<Self>,
+        cv: CommunicationValue,
+        timeout: Duration,
+    ) -> OmikronResult<CommunicationValue> {
+        let id = self.new_request_id();
+        let (tx, rx) = oneshot::channel();
+        self.waiting_tasks.insert(id, tx);
+
+        if let Err(e) = self.clone().send(&cv.with_id(id)).await {
+            self.waiting_tasks.remove(&id);
+            return Err(e);
+        }
+
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(response)) => Ok(response),
+            // The connection was cleaned up while waiting
+            Ok(Err(_)) => Err(OmikronError::NotConnected),
+            Err(_) => {
+                self.waiting_tasks.remove(&id);
+                Err(OmikronError::Timeout)
+            }
+        }
+    }
+
+    pub async fn get_location(&self) -> String {
+        self.location.read().await.clone()
+    }
+
+    pub async fn set_location(&self, location: String) {
+        *self.location.write().await = location;
+    }
+
+    pub async fn get_ping(&self) -> i64 {
+        *self.ping.read().await
+    }
+
+    pub fn reported_load(&self) -> Option<i64> {
+        Some(self.reported_load.load(Ordering::Relaxed)).filter(|load| *load >= 0)
+    }
+
+    pub async fn get_stats(&self) -> Option<OmikronStats> {
+        self.stats.read().await.clone()
+    }
+
+    /// An Omikron that reported once and then went quiet is unhealthy.
+    /// Omikrons that never report are judged by their connection alone.
+    pub async fn is_healthy(&self) -> bool {
+        match self.stats.read().await.as_ref() {
+            Some(stats) => !stats.is_stale(),
+            None => true,
+        }
+    }
+
+    /// Time since the transport connection was accepted.
+    pub fn connected_for(&self) -> Duration {
+        self.connected_at.elapsed()
+    }
+
+    /// A draining Omikron keeps its clients but is not handed out to new ones.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+}
+
+fn container_value(fields: &[(DataTypes, DataValue)], key: DataTypes) -> Option<&DataValue> {
+    fields.iter().find(|(k, _)| *k == key).map(|(_, v)| v)
+}
+
+fn status_counts_value(counts: &StatusCounts) -> DataValue {
+    DataValue::Array(
+        counts
+            .iter()
+            .map(|(status, count)| {
+                DataValue::Container(vec![
+                    (DataTypes::online_status, DataValue::Str(status.clone())),
+                    (DataTypes::amount, DataValue::Number(*count as i64)),
+                ])
+            })
+            .collect(),
+    )
+}
+
+/// Users registered on an IOTA, empty if they could not be loaded.
+async fn iota_user_ids(iota_id: i64) -> Vec<i64> {
+    iota_users::user_ids(iota_id).await.unwrap_or_default()
 }
 
 // ============================================================================
 // Server Startup
 // ============================================================================
 
-pub async fn start(port: u16) -> Result<(), Box<dyn std::error::Error>> {
-    let cert_pem = load_file_vec("certs", "transport_cert.pem").expect("Error loading Pemfile");
-
-    let key_pem = load_file_vec("certs", "transport_key.pem").expect("Error loading Keyfile");
-
-    let mut host: Host = epsilon_native::host(port, cert_pem, key_pem).await?;
+pub async fn start(config: &TransportConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let port = config.port;
+    let cert_pem = std::fs::read(resolve_path(&config.cert))?;
+    let key_pem = std::fs::read(resolve_path(&config.key))?;
+
+    let require_client_cert = config.require_client_cert;
+    let heartbeat = Heartbeat::from_config(config);
+    let mut host: Host = if require_client_cert {
+        let ca_pem = omikron_ca::load_ca_pem()?;
+        epsilon_native::host_with_client_auth(port, cert_pem, key_pem, ca_pem).await?
+    } else {
+        epsilon_native::host(port, cert_pem, key_pem).await?
+    };
     log!("OmikronServer listening on port {}", port);
+    health::set_listener(Listener::Epsilon, ListenerState::Bound);
+    presence_subscriptions::start();
+    if require_client_cert {
+        log!("  Omikron client certificates required");
+    }
 
-    while let Some((sender, mut receiver)) = host.next().await {
+    loop {
+        let next = tokio::select! {
+            next = host.next() => next,
+            _ = shutdown::requested() => break,
+        };
+        let Some((sender, mut receiver)) = next else {
+            break;
+        };
+        OPEN.fetch_add(1, Ordering::AcqRel);
         tokio::spawn(async move {
-            let conn = OmikronConnection::new(sender);
-            conn.handle(&mut receiver).await;
+            let conn = OmikronConnection::new(sender, require_client_cert);
+            conn.handle(&mut receiver, heartbeat).await;
+            OPEN.fetch_sub(1, Ordering::AcqRel);
         });
     }
 
     Ok(())
 }
+
+/// Waits until no message is being handled, at most `deadline`. Returns
+/// `false` if some were still running.
+pub async fn wait_for_handlers(deadline: Duration) -> bool {
+    wait_for_zero(&IN_FLIGHT, deadline).await
+}
+
+/// Waits until every connection finished its cleanup, at most `deadline`.
+/// Returns `false` if some were still running.
+pub async fn wait_for_connections(deadline: Duration) -> bool {
+    wait_for_zero(&OPEN, deadline).await
+}
+
+async fn wait_for_zero(counter: &AtomicUsize, deadline: Duration) -> bool {
+    let started = Instant::now();
+    while counter.load(Ordering::Acquire) > 0 {
+        if started.elapsed() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    true
+}
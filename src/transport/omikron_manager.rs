@@ -1,8 +1,24 @@
-use crate::transport::omikron_connection::OmikronConnection;
+use crate::config::{self, IpNetwork, SelectionStrategy};
+use crate::sql::user_online_tracker::{self, OmikronCounts, StatusCounts};
+use crate::transport::omikron_connection::{self, OmikronConnection};
+use crate::transport::presence_subscriptions;
+use crate::util::logger::PrintType;
+use crate::{log, log_err};
 use dashmap::DashMap;
+use epsilon_core::{CommunicationType, CommunicationValue, DataTypes, DataValue};
 use once_cell::sync::Lazy;
-use rand::prelude::IteratorRandom;
-use std::sync::Arc;
+use rand::{Rng, seq::SliceRandom};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::task::JoinHandle;
 
 pub static OMIKRON_CONNECTIONS: Lazy<DashMap<i64, Arc<OmikronConnection>>> =
     Lazy::new(|| DashMap::new());
@@ -16,24 +32,487 @@ pub async fn add_omikron(conn: Arc<OmikronConnection>) {
         }
     };
 
+    // A drain outlives the connection it was started on
+    if DRAINS.contains_key(&id) {
+        conn.set_draining(true);
+    }
+
+    // The old session's cleanup sees it was replaced and leaves the state alone
     if let Some(old) = OMIKRON_CONNECTIONS.insert(id, conn.clone()) {
-        old.close().await;
+        if !Arc::ptr_eq(&old, &conn) {
+            old.close().await;
+        }
     }
 }
 
-pub async fn remove_omikron(omikron_id: i64) {
-    OMIKRON_CONNECTIONS.remove(&omikron_id);
+/// Unregisters `conn` if it is still the registered connection of the
+/// Omikron. Returns `false` if it was replaced or removed already, in which
+/// case whatever is tracked belongs to someone else.
+pub fn remove_omikron(omikron_id: i64, conn: &Arc<OmikronConnection>) -> bool {
+    OMIKRON_CONNECTIONS
+        .remove_if(&omikron_id, |_, current| Arc::ptr_eq(current, conn))
+        .is_some()
 }
-pub async fn get_random_omikron() -> Result<Arc<OmikronConnection>, ()> {
-    let mut rng = rand::thread_rng();
 
-    let keys: Vec<_> = OMIKRON_CONNECTIONS.iter().map(|e| *e.key()).collect();
+/// Force-closes the live connection of an Omikron and drops everything it
+/// tracked. Returns `false` if it was not connected.
+pub async fn disconnect_omikron(omikron_id: i64) -> bool {
+    cancel_drain(omikron_id);
+    match OMIKRON_CONNECTIONS.remove(&omikron_id) {
+        Some((_, conn)) => {
+            conn.close().await;
+            forget_omikron(omikron_id);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Drops the subscriptions and tracker entries of an Omikron whose
+/// connection was unregistered.
+fn forget_omikron(omikron_id: i64) {
+    presence_subscriptions::remove_omikron(omikron_id);
+    user_online_tracker::untrack_omikron(omikron_id);
+}
+
+pub fn get_connection(omikron_id: i64) -> Option<Arc<OmikronConnection>> {
+    OMIKRON_CONNECTIONS.get(&omikron_id).map(|c| c.clone())
+}
+
+pub fn is_connected(omikron_id: i64) -> bool {
+    OMIKRON_CONNECTIONS.contains_key(&omikron_id)
+}
+
+/// Keeps the location used for selection in sync with the registry.
+pub async fn update_location(omikron_id: i64, location: String) {
+    if let Some(conn) = get_connection(omikron_id) {
+        conn.set_location(location).await;
+    }
+}
+
+/// Returns `false` if the Omikron is not connected. Clearing the flag also
+/// cancels a running drain.
+pub fn set_draining(omikron_id: i64, draining: bool) -> bool {
+    match OMIKRON_CONNECTIONS.get(&omikron_id) {
+        Some(conn) => {
+            if !draining {
+                cancel_drain(omikron_id);
+            }
+            conn.set_draining(draining);
+            true
+        }
+        None => false,
+    }
+}
+// ============================================================================
+// Routing
+// ============================================================================
+
+/// The Omikron a message for a user or IOTA has to go to: the one the user
+/// is tracked on, or the live primary of the IOTA.
+pub fn resolve_route(user_id: Option<i64>, iota_id: Option<i64>) -> Option<Arc<OmikronConnection>> {
+    let omikron_id = match (user_id, iota_id) {
+        (Some(user_id), _) => user_online_tracker::get_user_status(user_id)?.omikron_id,
+        (None, Some(iota_id)) => live_primary(iota_id)?,
+        (None, None) => return None,
+    };
+    get_connection(omikron_id)
+}
+
+/// The primary Omikron of an IOTA if it is connected, otherwise its oldest
+/// connected secondary. A draining primary stays tracked after it
+/// disconnected, so the elected one is not always reachable.
+pub fn live_primary(iota_id: i64) -> Option<i64> {
+    if let Some(primary) = user_online_tracker::get_iota_primary_omikron_connection(iota_id)
+        .filter(|primary| is_connected(*primary))
+    {
+        return Some(primary);
+    }
+    user_online_tracker::get_iota_omikron_connections(iota_id)?
+        .into_iter()
+        .find(|id| is_connected(*id))
+}
+
+/// Tells every Omikron the IOTA is connected to, and the one that lost the
+/// role, which Omikron is its primary now. No `omikron_id` means the IOTA
+/// has no connection left.
+pub fn primary_changed(iota_id: i64, previous: Option<i64>, primary: Option<i64>) {
+    let mut targets =
+        user_online_tracker::get_iota_omikron_connections(iota_id).unwrap_or_default();
+    if let Some(previous) = previous.filter(|previous| !targets.contains(previous)) {
+        targets.push(previous);
+    }
+    let connections: Vec<Arc<OmikronConnection>> =
+        targets.into_iter().filter_map(get_connection).collect();
+    if connections.is_empty() {
+        return;
+    }
+
+    let mut notice = CommunicationValue::new(CommunicationType::iota_primary_changed)
+        .add_data(DataTypes::iota_id, DataValue::Number(iota_id));
+    if let Some(primary) = primary {
+        notice = notice.add_data(DataTypes::omikron_id, DataValue::Number(primary));
+    }
+    tokio::spawn(async move {
+        for conn in connections {
+            if let Err(e) = conn.send_message(&notice).await {
+                log_err!(
+                    iota_id,
+                    PrintType::Omega,
+                    "Could not send iota_primary_changed: {}",
+                    e
+                );
+            }
+        }
+    });
+}
+
+// ============================================================================
+// Topology
+// ============================================================================
+
+pub struct OmikronTopology {
+    pub id: i64,
+    /// `None` for an Omikron that is only still tracked, e.g. while draining
+    pub connected_for: Option<Duration>,
+    pub draining: bool,
+    pub counts: OmikronCounts,
+}
+
+pub struct Topology {
+    pub users_by_status: StatusCounts,
+    pub iotas: usize,
+    pub omikrons: Vec<OmikronTopology>,
+}
+
+/// Counts from the tracker joined with the live connections. Every connected
+/// Omikron is listed, even without users or IOTAs.
+pub fn topology() -> Topology {
+    let mut counts = user_online_tracker::counts();
+    for entry in OMIKRON_CONNECTIONS.iter() {
+        counts.omikrons.entry(*entry.key()).or_default();
+    }
+
+    let omikrons = counts
+        .omikrons
+        .into_iter()
+        .map(|(id, counts)| {
+            let conn = get_connection(id);
+            OmikronTopology {
+                id,
+                connected_for: conn.as_ref().map(|c| c.connected_for()),
+                draining: conn.is_some_and(|c| c.is_draining()),
+                counts,
+            }
+        })
+        .collect();
+
+    Topology {
+        users_by_status: counts.users_by_status,
+        iotas: counts.iotas,
+        omikrons,
+    }
+}
+
+// ============================================================================
+// Draining
+// ============================================================================
+
+struct Drain {
+    id: u64,
+    timer: JoinHandle<()>,
+}
+
+// OmikronID -> the running drain, at most one per Omikron
+static DRAINS: Lazy<DashMap<i64, Drain>> = Lazy::new(DashMap::new);
+static NEXT_DRAIN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Asks an Omikron to move its clients elsewhere. It is no longer handed out,
+/// also after reconnecting, and its tracker entries are kept until `grace`
+/// has passed, even if it disconnects in the meantime. A new drain replaces
+/// the deadline of a running one.
+/// Returns the deadline in unix seconds, `None` if it is not connected.
+pub async fn drain_omikron(omikron_id: i64, grace: Duration) -> Option<u64> {
+    let conn = get_connection(omikron_id)?;
+    conn.set_draining(true);
+
+    let deadline = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        + grace.as_secs();
+
+    let notice = CommunicationValue::new(CommunicationType::drain)
+        .add_data(DataTypes::deadline, DataValue::Number(deadline as i64));
+    if let Err(e) = conn.clone().send_message(&notice).await {
+        log_err!(
+            omikron_id,
+            PrintType::Omega,
+            "Could not send drain notice: {}",
+            e
+        );
+    }
+    log!("Draining Omikron {} for {}s", omikron_id, grace.as_secs());
+
+    let drain_id = NEXT_DRAIN_ID.fetch_add(1, Ordering::Relaxed);
+    let timer = tokio::spawn(async move {
+        tokio::time::sleep(grace).await;
+        finish_drain(omikron_id, drain_id, conn).await;
+    });
+    if let Some(previous) = DRAINS.insert(
+        omikron_id,
+        Drain {
+            id: drain_id,
+            timer,
+        },
+    ) {
+        previous.timer.abort();
+    }
+
+    Some(deadline)
+}
+
+fn cancel_drain(omikron_id: i64) {
+    if let Some((_, drain)) = DRAINS.remove(&omikron_id) {
+        drain.timer.abort();
+    }
+}
+
+async fn finish_drain(omikron_id: i64, drain_id: u64, drained: Arc<OmikronConnection>) {
+    // Replaced or cancelled in the meantime
+    if DRAINS
+        .remove_if(&omikron_id, |_, drain| drain.id == drain_id)
+        .is_none()
+    {
+        return;
+    }
+
+    if let Some(current) = get_connection(omikron_id) {
+        // Back with a fresh connection that owns the tracker entries now and
+        // is handed out again
+        if !Arc::ptr_eq(&current, &drained) {
+            current.set_draining(false);
+            log!("Omikron {} drained and reconnected", omikron_id);
+            return;
+        }
+        if !remove_omikron(omikron_id, &drained) {
+            return;
+        }
+        drained.close().await;
+    }
+    forget_omikron(omikron_id);
+    log!("Omikron {} drained", omikron_id);
+}
+
+// ============================================================================
+// Shutdown
+// ============================================================================
+
+/// Omikrons are told to reconnect after a random delay in this range, so they
+/// do not all come back at once.
+const RECONNECT_HINT_SECS: RangeInclusive<i64> = 5..=30;
+
+/// How long closed connections get to finish their cleanup.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tells every Omikron that Omega is going away and when to reconnect, waits
+/// up to `grace` for messages that are being handled, closes the connections
+/// and waits for their cleanup. Draining Omikrons are untracked right away.
+pub async fn shutdown(grace: Duration) {
+    let connections: Vec<Arc<OmikronConnection>> = OMIKRON_CONNECTIONS
+        .iter()
+        .map(|entry| entry.value().clone())
+        .collect();
+
+    for conn in &connections {
+        let reconnect_secs = rand::thread_rng().gen_range(RECONNECT_HINT_SECS);
+        let notice = CommunicationValue::new(CommunicationType::shutdown)
+            .add_data(DataTypes::reconnect_secs, DataValue::Number(reconnect_secs));
+        if let Err(e) = conn.clone().send_message(&notice).await {
+            log_err!(0, PrintType::Omega, "Could not send shutdown notice: {}", e);
+        }
+    }
+
+    if !omikron_connection::wait_for_handlers(grace).await {
+        log!(
+            "[WARN] Omikron messages still running after {}s",
+            grace.as_secs()
+        );
+    }
+
+    for conn in connections {
+        conn.close().await;
+    }
+    if !omikron_connection::wait_for_connections(CLOSE_TIMEOUT).await {
+        log!(
+            "[WARN] Omikron connections still open after {}s",
+            CLOSE_TIMEOUT.as_secs()
+        );
+    }
+
+    let drained: Vec<i64> = DRAINS.iter().map(|entry| *entry.key()).collect();
+    for omikron_id in drained {
+        cancel_drain(omikron_id);
+        forget_omikron(omikron_id);
+    }
+}
 
-    if let Some(key) = keys.into_iter().choose(&mut rng) {
-        if let Some(entry) = OMIKRON_CONNECTIONS.get(&key) {
-            return Ok(entry.clone());
+// ============================================================================
+// Selection
+// ============================================================================
+
+/// What is known about the client asking for an Omikron.
+#[derive(Debug, Default)]
+pub struct SelectionHint {
+    /// Explicit `?region=` from the request
+    pub region: Option<String>,
+    pub client_ip: Option<String>,
+}
+
+#[derive(Clone)]
+struct Candidate {
+    conn: Arc<OmikronConnection>,
+    load: i64,
+    ping: i64,
+    /// Reported CPU usage, 0 if unknown
+    cpu_usage: i64,
+    location: String,
+    healthy: bool,
+}
+
+/// Picks an Omikron for a new client using the configured strategy.
+/// Draining Omikrons are never handed out, Omikrons with a stale stats report
+/// only if nothing else is left.
+pub async fn select_omikron(hint: &SelectionHint) -> Result<Arc<OmikronConnection>, ()> {
+    let conns: Vec<(i64, Arc<OmikronConnection>)> = OMIKRON_CONNECTIONS
+        .iter()
+        .filter(|e| !e.value().is_draining())
+        .map(|e| (*e.key(), e.value().clone()))
+        .collect();
+
+    let mut candidates = Vec::with_capacity(conns.len());
+    for (id, conn) in conns {
+        let load = conn
+            .reported_load()
+            .unwrap_or_else(|| user_online_tracker::count_users_on_omikron(id) as i64);
+        let stats = conn.get_stats().await;
+        candidates.push(Candidate {
+            load,
+            ping: conn.get_ping().await,
+            cpu_usage: stats.and_then(|s| s.cpu_usage).unwrap_or(0),
+            location: conn.get_location().await,
+            healthy: conn.is_healthy().await,
+            conn,
+        });
+    }
+    if candidates.iter().any(|c| c.healthy) {
+        candidates.retain(|c| c.healthy);
+    }
+
+    let selection = &config::get().selection;
+    let chosen = match selection.strategy {
+        SelectionStrategy::WeightedRandom => weighted_random(&candidates),
+        SelectionStrategy::LeastConnections => least_connections(&candidates),
+        SelectionStrategy::Region => {
+            let in_region: Vec<Candidate> = match resolve_region(hint, &selection.ip_regions) {
+                Some(region) => candidates
+                    .iter()
+                    .filter(|c| c.location.eq_ignore_ascii_case(&region))
+                    .cloned()
+                    .collect(),
+                None => Vec::new(),
+            };
+            least_connections(&in_region).or_else(|| weighted_random(&candidates))
         }
+    };
+
+    chosen.ok_or(())
+}
+
+fn resolve_region(
+    hint: &SelectionHint,
+    ip_regions: &BTreeMap<IpNetwork, String>,
+) -> Option<String> {
+    if let Some(region) = hint.region.as_ref().filter(|r| !r.is_empty()) {
+        return Some(region.clone());
+    }
+
+    // Forwarded headers may carry a port
+    let ip = hint.client_ip.as_deref()?;
+    let ip = ip
+        .parse::<IpAddr>()
+        .or_else(|_| ip.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()?;
+    ip_regions
+        .iter()
+        .filter(|(network, _)| network.contains(ip))
+        .max_by_key(|(network, _)| network.prefix_len())
+        .map(|(_, region)| region.clone())
+}
+
+fn weighted_random(candidates: &[Candidate]) -> Option<Arc<OmikronConnection>> {
+    let mut rng = rand::thread_rng();
+    candidates
+        .choose_weighted(&mut rng, |c| {
+            // An unknown ping (-1) counts as 0
+            let ping_factor = 1.0 + c.ping.max(0) as f64 / 100.0;
+            let cpu_factor = 1.0 + c.cpu_usage.clamp(0, 100) as f64 / 100.0;
+            1.0 / ((1 + c.load.max(0)) as f64 * ping_factor * cpu_factor)
+        })
+        .ok()
+        .map(|c| c.conn.clone())
+}
+
+fn least_connections(candidates: &[Candidate]) -> Option<Arc<OmikronConnection>> {
+    candidates
+        .iter()
+        .min_by_key(|c| (c.load, if c.ping < 0 { i64::MAX } else { c.ping }))
+        .map(|c| c.conn.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regions() -> BTreeMap<IpNetwork, String> {
+        [
+            ("10.1.0.0/16", "eu-central"),
+            ("10.1.2.0/24", "eu-west"),
+            ("2001:db8::/32", "us-east"),
+        ]
+        .into_iter()
+        .map(|(network, region)| (network.parse().unwrap(), region.to_string()))
+        .collect()
+    }
+
+    fn region_for(client_ip: &str) -> Option<String> {
+        let hint = SelectionHint {
+            region: None,
+            client_ip: Some(client_ip.to_string()),
+        };
+        resolve_region(&hint, &regions())
     }
 
-    Err(())
+    #[test]
+    fn longest_prefix_wins() {
+        assert_eq!(region_for("10.1.9.9").as_deref(), Some("eu-central"));
+        assert_eq!(region_for("10.1.2.3").as_deref(), Some("eu-west"));
+        assert_eq!(region_for("10.100.2.3"), None);
+        assert_eq!(region_for("2001:db8::7").as_deref(), Some("us-east"));
+        assert_eq!(region_for("::ffff:10.1.2.3").as_deref(), Some("eu-west"));
+        assert_eq!(region_for("10.1.2.3:4711").as_deref(), Some("eu-west"));
+        assert_eq!(region_for("not an address"), None);
+    }
+
+    #[test]
+    fn explicit_region_wins() {
+        let hint = SelectionHint {
+            region: Some("ap-south".to_string()),
+            client_ip: Some("10.1.2.3".to_string()),
+        };
+        assert_eq!(
+            resolve_region(&hint, &regions()).as_deref(),
+            Some("ap-south")
+        );
+    }
 }
@@ -0,0 +1,145 @@
+use crate::log_err;
+use crate::sql::{connection_status::UserStatus, user_online_tracker};
+use crate::transport::omikron_manager;
+use crate::util::logger::PrintType;
+use dashmap::{DashMap, DashSet};
+use epsilon_core::{CommunicationType, CommunicationValue, DataTypes, DataValue};
+use once_cell::sync::Lazy;
+use std::{collections::HashMap, collections::HashSet, time::Duration};
+use tokio::time::interval;
+
+// ============================================================================
+// Configuration
+// ============================================================================
+
+/// Changes within one window are sent as a single event per Omikron.
+const COALESCE_WINDOW: Duration = Duration::from_millis(250);
+
+// ============================================================================
+// State
+// ============================================================================
+
+// UserID -> OmikronIDs interested in it
+static SUBSCRIBERS: Lazy<DashMap<i64, HashSet<i64>>> = Lazy::new(DashMap::new);
+
+// OmikronID -> UserIDs it subscribed to
+static SUBSCRIPTIONS: Lazy<DashMap<i64, HashSet<i64>>> = Lazy::new(DashMap::new);
+
+// UserIDs whose status changed since the last flush
+static PENDING: Lazy<DashSet<i64>> = Lazy::new(DashSet::new);
+
+// ============================================================================
+// Subscriptions
+// ============================================================================
+
+pub fn subscribe(omikron_id: i64, user_ids: &[i64]) {
+    let mut own = SUBSCRIPTIONS.entry(omikron_id).or_default();
+    for user_id in user_ids {
+        if own.insert(*user_id) {
+            SUBSCRIBERS.entry(*user_id).or_default().insert(omikron_id);
+        }
+    }
+}
+
+pub fn unsubscribe(omikron_id: i64, user_ids: &[i64]) {
+    if let Some(mut own) = SUBSCRIPTIONS.get_mut(&omikron_id) {
+        for user_id in user_ids {
+            if own.remove(user_id) {
+                remove_subscriber(*user_id, omikron_id);
+            }
+        }
+    }
+}
+
+/// Drops every subscription of a disconnected Omikron.
+pub fn remove_omikron(omikron_id: i64) {
+    if let Some((_, user_ids)) = SUBSCRIPTIONS.remove(&omikron_id) {
+        for user_id in user_ids {
+            remove_subscriber(user_id, omikron_id);
+        }
+    }
+}
+
+fn remove_subscriber(user_id: i64, omikron_id: i64) {
+    if let Some(mut subscribers) = SUBSCRIBERS.get_mut(&user_id) {
+        subscribers.remove(&omikron_id);
+    }
+    SUBSCRIBERS.remove_if(&user_id, |_, subscribers| subscribers.is_empty());
+}
+
+// ============================================================================
+// Fan-out
+// ============================================================================
+
+/// Called by the tracker whenever the status of a user changes.
+pub fn status_changed(user_id: i64) {
+    if SUBSCRIBERS.contains_key(&user_id) {
+        PENDING.insert(user_id);
+    }
+}
+
+/// Sends the coalesced `user_status_changed` events every `COALESCE_WINDOW`.
+pub fn start() {
+    tokio::spawn(async {
+        let mut ticker = interval(COALESCE_WINDOW);
+        loop {
+            ticker.tick().await;
+            flush().await;
+        }
+    });
+}
+
+async fn flush() {
+    let changed: Vec<i64> = PENDING.iter().map(|id| *id).collect();
+    if changed.is_empty() {
+        return;
+    }
+
+    // OmikronID -> current state of every changed user it is interested in
+    let mut events: HashMap<i64, Vec<DataValue>> = HashMap::new();
+    for user_id in changed {
+        PENDING.remove(&user_id);
+        let Some(subscribers) = SUBSCRIBERS.get(&user_id).map(|s| s.clone()) else {
+            continue;
+        };
+
+        let entry = status_entry(user_id);
+        for omikron_id in subscribers {
+            events.entry(omikron_id).or_default().push(entry.clone());
+        }
+    }
+
+    for (omikron_id, users) in events {
+        let Some(conn) = omikron_manager::get_connection(omikron_id) else {
+            continue;
+        };
+        let event = CommunicationValue::new(CommunicationType::user_status_changed)
+            .add_data(DataTypes::users, DataValue::Array(users));
+        if let Err(e) = conn.send_message(&event).await {
+            log_err!(
+                omikron_id,
+                PrintType::Omega,
+                "Could not send user_status_changed: {}",
+                e
+            );
+        }
+    }
+}
+
+fn status_entry(user_id: i64) -> DataValue {
+    let mut entry = vec![(DataTypes::user_id, DataValue::Number(user_id))];
+    match user_online_tracker::get_user_status(user_id) {
+        Some(status) => {
+            entry.push((
+                DataTypes::online_status,
+                DataValue::Str(status.connection_type.to_string()),
+            ));
+            entry.push((DataTypes::omikron_id, DataValue::Number(status.omikron_id)));
+        }
+        None => entry.push((
+            DataTypes::online_status,
+            DataValue::Str(UserStatus::iota_offline.to_string()),
+        )),
+    }
+    DataValue::Container(entry)
+}
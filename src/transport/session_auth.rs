@@ -0,0 +1,311 @@
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use epsilon_core::{CommunicationValue, DataTypes, DataValue};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use x448::{PublicKey, Secret};
+
+// Both sides derive the same keys from their static x448 keys, both nonces
+// and both identities:
+//
+//   salt = omikron_nonce || omega_nonce
+//   info = label || omikron_id || omikron_public_key || omega_public_key
+//
+// The proof key authenticates the handshake in both directions, the session
+// key authenticates every message sent after it.
+
+// ============================================================================
+// Configuration
+// ============================================================================
+
+pub const NONCE_LEN: usize = 32;
+
+const PROOF_LABEL: &[u8] = b"omega-omikron proof v1";
+const SESSION_LABEL: &[u8] = b"omega-omikron session v1";
+const OMEGA_ROLE: &[u8] = b"omega";
+const OMIKRON_ROLE: &[u8] = b"omikron";
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub fn nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+/// Decodes a nonce sent by an Omikron, `None` unless it has the full length.
+pub fn decode_nonce(encoded: &str) -> Option<[u8; NONCE_LEN]> {
+    STANDARD.decode(encoded).ok()?.try_into().ok()
+}
+
+// ============================================================================
+// Handshake
+// ============================================================================
+
+pub struct HandshakeKeys {
+    proof_key: [u8; 32],
+    session_key: [u8; 32],
+}
+
+impl HandshakeKeys {
+    /// `None` if the key agreement fails, e.g. for a low order public key.
+    pub fn derive(
+        secret: &Secret,
+        omikron_public_key: &PublicKey,
+        omega_public_key: &PublicKey,
+        omikron_id: i64,
+        omikron_nonce: &[u8; NONCE_LEN],
+        omega_nonce: &[u8; NONCE_LEN],
+    ) -> Option<Self> {
+        let shared = secret.as_diffie_hellman(omikron_public_key)?;
+
+        let mut salt = Vec::with_capacity(2 * NONCE_LEN);
+        salt.extend_from_slice(omikron_nonce);
+        salt.extend_from_slice(omega_nonce);
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+
+        let expand = |label: &[u8]| {
+            let mut info = label.to_vec();
+            info.extend_from_slice(&omikron_id.to_be_bytes());
+            info.extend_from_slice(omikron_public_key.as_bytes());
+            info.extend_from_slice(omega_public_key.as_bytes());
+
+            let mut key = [0u8; 32];
+            hkdf.expand(&info, &mut key).ok()?;
+            Some(key)
+        };
+
+        Some(Self {
+            proof_key: expand(PROOF_LABEL)?,
+            session_key: expand(SESSION_LABEL)?,
+        })
+    }
+
+    /// Sent with the challenge so the Omikron can check it talks to Omega.
+    pub fn omega_proof(&self) -> String {
+        STANDARD.encode(mac(&self.proof_key, OMEGA_ROLE))
+    }
+
+    pub fn verify_omikron_proof(&self, proof: &str) -> bool {
+        let Ok(proof) = STANDARD.decode(proof) else {
+            return false;
+        };
+        let mut hmac = new_hmac(&self.proof_key);
+        hmac.update(OMIKRON_ROLE);
+        hmac.verify_slice(&proof).is_ok()
+    }
+
+    pub fn into_session(self) -> Session {
+        Session {
+            key: self.session_key,
+            sent: 0,
+            received: 0,
+        }
+    }
+}
+
+// ============================================================================
+// Session
+// ============================================================================
+
+/// Authenticates the messages of one connection after the handshake.
+///
+/// The MAC covers the direction, a per-direction counter, the message id,
+/// sender and receiver, the type and the data, so messages cannot be altered,
+/// replayed, reflected or routed to someone else.
+pub struct Session {
+    key: [u8; 32],
+    sent: u64,
+    received: u64,
+}
+
+impl Session {
+    pub fn sign(&mut self, cv: &CommunicationValue) -> CommunicationValue {
+        let tag = self.tag(OMEGA_ROLE, self.sent, cv).finalize().into_bytes();
+        self.sent += 1;
+        cv.clone()
+            .add_data(DataTypes::mac, DataValue::Str(STANDARD.encode(tag)))
+    }
+
+    /// The counter only advances for valid messages.
+    pub fn verify(&mut self, cv: &CommunicationValue) -> bool {
+        let Some(Ok(tag)) = cv
+            .get_data(DataTypes::mac)
+            .as_str()
+            .map(|tag| STANDARD.decode(tag))
+        else {
+            return false;
+        };
+
+        let valid = self
+            .tag(OMIKRON_ROLE, self.received, cv)
+            .verify_slice(&tag)
+            .is_ok();
+        if valid {
+            self.received += 1;
+        }
+        valid
+    }
+
+    fn tag(&self, role: &[u8], counter: u64, cv: &CommunicationValue) -> HmacSha256 {
+        let mut hmac = new_hmac(&self.key);
+        hmac.update(role);
+        hmac.update(&counter.to_be_bytes());
+        hmac.update(&cv.get_id().to_be_bytes());
+        hmac.update(&cv.get_sender().to_be_bytes());
+        hmac.update(&cv.get_receiver().to_be_bytes());
+        encode_str(&mut hmac, &cv.get_type().to_string());
+
+        let fields: Vec<_> = cv
+            .get_data_container()
+            .iter()
+            .filter(|(key, _)| **key != DataTypes::mac)
+            .collect();
+        hmac.update(&(fields.len() as u64).to_be_bytes());
+        for (key, value) in fields {
+            encode_str(&mut hmac, &key.to_string());
+            encode_value(&mut hmac, value);
+        }
+        hmac
+    }
+}
+
+// ============================================================================
+// Encoding
+// ============================================================================
+
+fn new_hmac(key: &[u8; 32]) -> HmacSha256 {
+    HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length")
+}
+
+fn mac(key: &[u8; 32], message: &[u8]) -> Vec<u8> {
+    let mut hmac = new_hmac(key);
+    hmac.update(message);
+    hmac.finalize().into_bytes().to_vec()
+}
+
+// Numbers have a fixed width, every other item is tagged and length
+// prefixed, so distinct messages never encode to the same bytes.
+
+fn encode_str(hmac: &mut HmacSha256, value: &str) {
+    hmac.update(&(value.len() as u64).to_be_bytes());
+    hmac.update(value.as_bytes());
+}
+
+fn encode_value(hmac: &mut HmacSha256, value: &DataValue) {
+    if let Some(value) = value.as_str() {
+        hmac.update(b"s");
+        encode_str(hmac, value);
+    } else if let Some(value) = value.as_number() {
+        hmac.update(b"n");
+        hmac.update(&value.to_be_bytes());
+    } else if let Some(value) = value.as_bool() {
+        hmac.update(if value { b"t" } else { b"f" });
+    } else if let DataValue::Array(items) = value {
+        hmac.update(b"a");
+        hmac.update(&(items.len() as u64).to_be_bytes());
+        for item in items {
+            encode_value(hmac, item);
+        }
+    } else if let DataValue::Container(fields) = value {
+        hmac.update(b"c");
+        hmac.update(&(fields.len() as u64).to_be_bytes());
+        for (key, value) in fields {
+            encode_str(hmac, &key.to_string());
+            encode_value(hmac, value);
+        }
+    } else {
+        hmac.update(b"z");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use epsilon_core::CommunicationType;
+
+    fn session() -> Session {
+        HandshakeKeys {
+            proof_key: [1; 32],
+            session_key: [2; 32],
+        }
+        .into_session()
+    }
+
+    /// Signs like the Omikron end of the session does.
+    fn omikron_sign(omikron: &mut Session, cv: &CommunicationValue) -> CommunicationValue {
+        let tag = omikron
+            .tag(OMIKRON_ROLE, omikron.sent, cv)
+            .finalize()
+            .into_bytes();
+        omikron.sent += 1;
+        cv.clone()
+            .add_data(DataTypes::mac, DataValue::Str(STANDARD.encode(tag)))
+    }
+
+    fn message(user_id: i64) -> CommunicationValue {
+        CommunicationValue::new(CommunicationType::route_message)
+            .with_id(7)
+            .with_sender(11)
+            .with_receiver(12)
+            .add_data(DataTypes::user_id, DataValue::Number(user_id))
+    }
+
+    #[test]
+    fn signed_messages_verify_in_order() {
+        let (mut omega, mut omikron) = (session(), session());
+        let first = omikron_sign(&mut omikron, &message(1));
+        let second = omikron_sign(&mut omikron, &message(2));
+
+        assert!(omega.verify(&first));
+        assert!(omega.verify(&second));
+    }
+
+    #[test]
+    fn altered_messages_are_rejected() {
+        let (mut omega, mut omikron) = (session(), session());
+        let signed = omikron_sign(&mut omikron, &message(1));
+        let mac = signed.get_data(DataTypes::mac).clone();
+
+        let altered = [
+            message(2),
+            message(1).with_id(8),
+            message(1).with_sender(13),
+            message(1).with_receiver(13),
+        ];
+        for altered in altered {
+            assert!(!omega.verify(&altered.add_data(DataTypes::mac, mac.clone())));
+        }
+        assert!(!omega.verify(&message(1)));
+        // Failed attempts do not advance the counter
+        assert!(omega.verify(&signed));
+    }
+
+    #[test]
+    fn replayed_messages_are_rejected() {
+        let (mut omega, mut omikron) = (session(), session());
+        let first = omikron_sign(&mut omikron, &message(1));
+        let second = omikron_sign(&mut omikron, &message(1));
+
+        assert!(omega.verify(&first));
+        assert!(!omega.verify(&first));
+        assert!(omega.verify(&second));
+        assert!(!omega.verify(&second));
+    }
+
+    #[test]
+    fn reflected_messages_are_rejected() {
+        let (mut omega, mut other) = (session(), session());
+        let sent = omega.sign(&message(1));
+        assert!(!other.verify(&sent));
+
+        let keys = HandshakeKeys {
+            proof_key: [1; 32],
+            session_key: [2; 32],
+        };
+        assert!(!keys.verify_omikron_proof(&keys.omega_proof()));
+        let omikron_proof = STANDARD.encode(mac(&keys.proof_key, OMIKRON_ROLE));
+        assert!(keys.verify_omikron_proof(&omikron_proof));
+    }
+}
@@ -40,12 +40,12 @@ pub fn secret_key_to_base64(secret: &Secret) -> String {
 }
 
 pub fn load_public_key(base64_pub: &str) -> Option<PublicKey> {
-    let bytes = STANDARD.decode(base64_pub).unwrap();
+    let bytes = STANDARD.decode(base64_pub).ok()?;
     PublicKey::from_bytes(&bytes)
 }
 
 pub fn load_secret_key(base64_secret: &str) -> Option<Secret> {
-    let bytes = STANDARD.decode(base64_secret).unwrap();
+    let bytes = STANDARD.decode(base64_secret).ok()?;
     Secret::from_bytes(&bytes)
 }
 
@@ -2,17 +2,17 @@ use std::{
     collections::{BTreeMap, HashMap},
     fs::{self, OpenOptions},
     io::Write,
-    path::Path,
     sync::{OnceLock, mpsc},
     thread,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use crate::config::{LoggerConfig, resolve_path};
 use ansi_term::Color;
 use epsilon_core::{CommunicationValue, DataTypes, DataValue};
 use json::JsonValue;
 
-static LOGGER: OnceLock<mpsc::Sender<LogMessage>> = OnceLock::new();
+static LOGGER: OnceLock<mpsc::Sender<LogEvent>> = OnceLock::new();
 
 #[derive(Clone, Copy)]
 #[allow(unused)]
@@ -25,6 +25,12 @@ pub enum PrintType {
     General,
 }
 
+enum LogEvent {
+    Message(LogMessage),
+    /// Answered once everything sent before it is written
+    Flush(mpsc::SyncSender<()>),
+}
+
 struct LogMessage {
     timestamp_ms: u128,
     sender: Option<i64>,
@@ -34,12 +40,13 @@ struct LogMessage {
     message: String,
 }
 
-pub fn startup() {
-    let (tx, rx) = mpsc::channel::<LogMessage>();
+pub fn startup(config: &LoggerConfig) {
+    let (tx, rx) = mpsc::channel::<LogEvent>();
     LOGGER.set(tx).expect("Logger already initialized");
 
+    let log_dir = resolve_path(&config.directory);
     thread::spawn(move || {
-        let log_dir = Path::new("logs");
+        let log_dir = log_dir.as_path();
         fs::create_dir_all(log_dir).expect("Failed to create log directory");
 
         let start_ts = SystemTime::now()
@@ -54,7 +61,15 @@ pub fn startup() {
             .open(path)
             .expect("Failed to open log file");
 
-        for msg in rx {
+        for event in rx {
+            let msg = match event {
+                LogEvent::Message(msg) => msg,
+                LogEvent::Flush(done) => {
+                    let _ = file.flush();
+                    let _ = done.send(());
+                    continue;
+                }
+            };
             let ts = fixed_box(&msg.timestamp_ms.to_string(), 13);
             let sender = match msg.sender {
                 Some(id) => fixed_box(&id.to_string(), 19),
@@ -103,7 +118,7 @@ pub fn log_internal(
     message: String,
 ) {
     if let Some(tx) = LOGGER.get() {
-        let _ = tx.send(LogMessage {
+        let _ = tx.send(LogEvent::Message(LogMessage {
             timestamp_ms: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
@@ -113,7 +128,17 @@ pub fn log_internal(
             kind,
             is_error,
             message,
-        });
+        }));
+    }
+}
+
+/// Blocks until every message logged so far is written, at most `timeout`.
+pub fn flush(timeout: Duration) {
+    if let Some(tx) = LOGGER.get() {
+        let (done_tx, done_rx) = mpsc::sync_channel(1);
+        if tx.send(LogEvent::Flush(done_tx)).is_ok() {
+            let _ = done_rx.recv_timeout(timeout);
+        }
     }
 }
 
@@ -350,3 +375,18 @@ macro_rules! log_cv_out {
         $crate::util::logger::log_cv_internal("< ", &$cv, None)
     };
 }
+
+/// Security relevant events such as failed Omikron handshakes. Always written,
+/// with the Omikron id (or 0) as sender.
+#[macro_export]
+macro_rules! log_audit {
+    ($sender:expr, $($arg:tt)*) => {
+        $crate::util::logger::log_internal(
+            Some($sender),
+            $crate::util::logger::PrintType::Omega,
+            "AUDIT",
+            true,
+            format!($($arg)*)
+        )
+    };
+}
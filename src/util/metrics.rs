@@ -0,0 +1,316 @@
+use crate::server::short_link;
+use crate::sql::{sql, user_online_tracker};
+use crate::transport::omikron_manager;
+use epsilon_core::CommunicationValue;
+use once_cell::sync::Lazy;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        Mutex, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+// Metrics are kept in plain maps and rendered in the Prometheus text format
+// when `/metrics` on the admin listener is scraped.
+
+/// Upper bounds in seconds, shared by every latency histogram.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+}
+
+// (route, status) -> requests
+static HTTP_REQUESTS: Lazy<Mutex<BTreeMap<(String, u16), u64>>> = Lazy::new(Default::default);
+// route -> latency
+static HTTP_LATENCY: Lazy<Mutex<BTreeMap<String, Histogram>>> = Lazy::new(Default::default);
+// (direction, CommunicationType) -> messages
+static MESSAGES: Lazy<Mutex<BTreeMap<(&'static str, String), u64>>> = Lazy::new(Default::default);
+// OmikronError variant -> errors
+static HANDLER_ERRORS: Lazy<Mutex<BTreeMap<&'static str, u64>>> = Lazy::new(Default::default);
+// sql function -> latency
+static SQL_LATENCY: Lazy<Mutex<BTreeMap<&'static str, Histogram>>> = Lazy::new(Default::default);
+
+static SHORT_LINK_HITS: AtomicU64 = AtomicU64::new(0);
+static SHORT_LINK_MISSES: AtomicU64 = AtomicU64::new(0);
+
+fn locked<T>(map: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    map.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// ============================================================================
+// Recording
+// ============================================================================
+
+pub fn record_http(route: String, status: u16, elapsed: Duration) {
+    *locked(&HTTP_REQUESTS)
+        .entry((route.clone(), status))
+        .or_default() += 1;
+    locked(&HTTP_LATENCY)
+        .entry(route)
+        .or_default()
+        .observe(elapsed);
+}
+
+pub fn record_message(direction: Direction, cv: &CommunicationValue) {
+    let kind = format!("{:?}", cv.get_type());
+    *locked(&MESSAGES)
+        .entry((direction.as_str(), kind))
+        .or_default() += 1;
+}
+
+pub fn record_handler_error(kind: &'static str) {
+    *locked(&HANDLER_ERRORS).entry(kind).or_default() += 1;
+}
+
+pub fn record_redirect(hit: bool) {
+    let counter = if hit {
+        &SHORT_LINK_HITS
+    } else {
+        &SHORT_LINK_MISSES
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records the lifetime of the guard as the latency of `query`.
+pub struct SqlTimer {
+    query: &'static str,
+    started: Instant,
+}
+
+pub fn time_sql(query: &'static str) -> SqlTimer {
+    SqlTimer {
+        query,
+        started: Instant::now(),
+    }
+}
+
+impl Drop for SqlTimer {
+    fn drop(&mut self) {
+        locked(&SQL_LATENCY)
+            .entry(self.query)
+            .or_default()
+            .observe(self.started.elapsed());
+    }
+}
+
+// ============================================================================
+// Rendering
+// ============================================================================
+
+pub async fn render() -> String {
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "omega_http_requests_total",
+        "counter",
+        "HTTP requests by route and status.",
+    );
+    for ((route, status), count) in locked(&HTTP_REQUESTS).iter() {
+        let labels = format!("route=\"{}\",status=\"{}\"", escape(route), status);
+        sample(&mut out, "omega_http_requests_total", &labels, *count);
+    }
+
+    header(
+        &mut out,
+        "omega_http_request_duration_seconds",
+        "histogram",
+        "HTTP request latency by route.",
+    );
+    for (route, histogram) in locked(&HTTP_LATENCY).iter() {
+        let labels = format!("route=\"{}\"", escape(route));
+        write_histogram(
+            &mut out,
+            "omega_http_request_duration_seconds",
+            &labels,
+            histogram,
+        );
+    }
+
+    header(
+        &mut out,
+        "omega_omikron_messages_total",
+        "counter",
+        "Epsilon messages exchanged with Omikrons by direction and type.",
+    );
+    for ((direction, kind), count) in locked(&MESSAGES).iter() {
+        let labels = format!("direction=\"{}\",type=\"{}\"", direction, escape(kind));
+        sample(&mut out, "omega_omikron_messages_total", &labels, *count);
+    }
+
+    header(
+        &mut out,
+        "omega_omikron_handler_errors_total",
+        "counter",
+        "Failed Omikron messages by error.",
+    );
+    for (kind, count) in locked(&HANDLER_ERRORS).iter() {
+        let labels = format!("error=\"{}\"", kind);
+        sample(
+            &mut out,
+            "omega_omikron_handler_errors_total",
+            &labels,
+            *count,
+        );
+    }
+
+    header(
+        &mut out,
+        "omega_sql_query_duration_seconds",
+        "histogram",
+        "Database query latency by query.",
+    );
+    for (query, histogram) in locked(&SQL_LATENCY).iter() {
+        let labels = format!("query=\"{}\"", query);
+        write_histogram(
+            &mut out,
+            "omega_sql_query_duration_seconds",
+            &labels,
+            histogram,
+        );
+    }
+
+    if let Some((size, idle)) = sql::pool_usage().await {
+        header(
+            &mut out,
+            "omega_sql_pool_connections",
+            "gauge",
+            "Open database connections by state.",
+        );
+        let active = (size as usize).saturating_sub(idle);
+        sample(
+            &mut out,
+            "omega_sql_pool_connections",
+            "state=\"active\"",
+            active as u64,
+        );
+        sample(
+            &mut out,
+            "omega_sql_pool_connections",
+            "state=\"idle\"",
+            idle as u64,
+        );
+    }
+
+    let (users, iotas) = user_online_tracker::totals();
+    gauge(
+        &mut out,
+        "omega_omikrons_authenticated",
+        "Authenticated Omikron connections.",
+        omikron_manager::OMIKRON_CONNECTIONS.len() as u64,
+    );
+    gauge(
+        &mut out,
+        "omega_tracked_users",
+        "Users known to the online tracker.",
+        users as u64,
+    );
+    gauge(
+        &mut out,
+        "omega_tracked_iotas",
+        "IOTAs with at least one Omikron connection.",
+        iotas as u64,
+    );
+    gauge(
+        &mut out,
+        "omega_short_links",
+        "Short links held in memory.",
+        short_link::count() as u64,
+    );
+
+    header(
+        &mut out,
+        "omega_short_link_redirects_total",
+        "counter",
+        "Short link redirects by result.",
+    );
+    sample(
+        &mut out,
+        "omega_short_link_redirects_total",
+        "result=\"hit\"",
+        SHORT_LINK_HITS.load(Ordering::Relaxed),
+    );
+    sample(
+        &mut out,
+        "omega_short_link_redirects_total",
+        "result=\"miss\"",
+        SHORT_LINK_MISSES.load(Ordering::Relaxed),
+    );
+
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: u64) {
+    let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn write_histogram(out: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+    for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"{}\"}} {}",
+            name, labels, bound, count
+        );
+    }
+    let _ = writeln!(
+        out,
+        "{}_bucket{{{},le=\"+Inf\"}} {}",
+        name, labels, histogram.count
+    );
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum);
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -2,3 +2,5 @@ pub mod crypto_helper;
 pub mod crypto_util;
 pub mod file_util;
 pub mod logger;
+pub mod metrics;
+pub mod shutdown;
@@ -0,0 +1,46 @@
+use crate::log;
+use once_cell::sync::Lazy;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// How long in-flight HTTP requests and Omikron messages get to finish.
+pub const GRACE: Duration = Duration::from_secs(30);
+
+static SHUTDOWN: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+
+pub fn trigger() {
+    SHUTDOWN.send_replace(true);
+}
+
+pub fn is_shutting_down() -> bool {
+    *SHUTDOWN.borrow()
+}
+
+/// Resolves once shutdown has been triggered.
+pub async fn requested() {
+    let mut rx = SHUTDOWN.subscribe();
+    let _ = rx.wait_for(|shutting_down| *shutting_down).await;
+}
+
+/// Triggers shutdown on SIGINT or SIGTERM.
+pub fn listen_for_signals() {
+    tokio::spawn(async {
+        #[cfg(unix)]
+        let terminate = async {
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                Ok(mut signal) => {
+                    signal.recv().await;
+                }
+                Err(_) => std::future::pending::<()>().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => log!("SIGINT received, shutting down"),
+            _ = terminate => log!("SIGTERM received, shutting down"),
+        }
+        trigger();
+    });
+}